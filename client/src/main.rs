@@ -1,169 +1,111 @@
 use mimalloc::MiMalloc;
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
-use std::{
-    collections::{HashMap, HashSet},
-    fs,
-    io::{self, Read},
-    sync::Arc,
-};
-use utils::{cbf, encryption::TokenVerifier, split_strings::SplitStrings};
+use std::fs;
 
-struct Config {
-    server_url: String,
-    token: String,
-    music_dir: String,
-}
+use sync_core::{events::Events, CancellationToken, Config, Error, SyncSession};
 
-impl Config {
-    fn new() -> io::Result<Self> {
-        let mut file = if let Ok(file) = fs::File::open("config.conf") {
-            file
-        } else {
+mod report;
+mod snapshot;
+mod trash;
+
+const CONFIG_PATH: &str = "config.conf";
+const STATE_PATH: &str = "state.json";
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let json = std::env::args().any(|arg| arg == "--json");
+    let events = Events::new(move |event| report::render(event, json));
+
+    let buffer = match fs::read_to_string(CONFIG_PATH) {
+        Ok(buffer) => buffer,
+        Err(_) => {
             fs::write(
-                "config.conf",
+                CONFIG_PATH,
                 "server_url_here\ntoken_here\nmusic_dir_path_here",
             )?;
-            eprintln!("Please fill out the config.conf file and run the program again");
+            events.warn("Please fill out the config.conf file and run the program again");
             std::process::exit(1);
-        };
-
-        let mut buffer = String::new();
-        file.read_to_string(&mut buffer)?;
-
-        let mut lines = buffer.lines();
-
-        Ok(Self {
-            server_url: lines.next().unwrap().to_string(),
-            token: lines.next().unwrap().to_string(),
-            music_dir: lines.next().unwrap().to_string(),
-        })
-    }
-}
-
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let config = Arc::new(Config::new()?);
-
-    let token_verifier = TokenVerifier::new(&config.token);
-    let encrypted_token = token_verifier.encrypt(config.token.as_bytes());
-
-    let (file_names, file_entries) = utils::get_files(&config.music_dir)?;
-
-    let client = reqwest::blocking::Client::new();
-
-    let response = client
-        .get(format!("{}/sync", config.server_url))
-        .header("Authorization", &encrypted_token)
-        .body(utils::join_hashset(&file_names, '|'))
-        .send();
-
-    let response = match response {
-        Ok(response) => response,
-        Err(err) => {
-            eprintln!("Failed to sync files: {}", err);
-            return Ok(());
         }
     };
+    let (config, warnings) = Config::parse(&buffer)?;
+    for warning in warnings {
+        events.warn(warning);
+    }
 
-    if response.status().is_success() {
-        let content_type = response.headers().get("content-type");
-
-        match content_type {
-            // Basically, if the content type is application/octet-stream
-            Some(_) => {
-                let buffer = response.bytes()?;
-                let mut cursor = std::io::Cursor::new(buffer);
-
-                let (missing_files, entries) = cbf::read(&mut cursor)?;
-
-                println!("The server is missing {} files", missing_files.len());
-                println!("The client is missing {} files", entries.len());
-
-                let config_clone = config.clone();
-
-                let network_thead = if !missing_files.is_empty() {
-                    Some(std::thread::spawn(move || {
-                        sync_missing_files(
-                            &client,
-                            &config_clone,
-                            &encrypted_token,
-                            &file_entries,
-                            &missing_files,
-                        )
-                        .expect("Failed to sync missing files!");
-                    }))
-                } else {
-                    None
-                };
-
-                entries.into_par_iter().for_each(|(name, data)| {
-                    fs::write(format!("{}/{}", config.music_dir, name), data).unwrap();
-                });
-
-                if let Some(network_thead) = network_thead {
-                    network_thead.join().unwrap();
-                }
+    // cancelling stops the sync with everything in flight, partial files remove themselves
+    // and the files that were complete are kept in the state
+    let cancel = CancellationToken::new();
+    tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                cancel.cancel();
             }
-            None => {
-                let response_text = response.text()?;
-
-                match response_text.as_str() {
-                    "synced" => {
-                        println!("Already Synced!");
-                    }
-
-                    _ => {
-                        let missing_files_names =
-                            SplitStrings::new(&response_text, '|').collect::<HashSet<String>>();
+        }
+    });
+
+    let args: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|arg| arg != "--json")
+        .collect();
+    if !args.is_empty() {
+        let result = if args[0] == "snapshots" || args.iter().any(|arg| arg == "--snapshot") {
+            snapshot::run(&config, &args, &events, json, cancel.clone()).await
+        } else {
+            trash::run(&config, &args, &events, json).await
+        };
+        if let Err(err) = result {
+            events.warn(err.to_string());
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
-                        println!("The server is missing {} files", missing_files_names.len());
+    // every library is synced on its own and remembers its own state, one that fails
+    // doesn't keep the others from syncing
+    let configs = config.per_library();
+    let mut failed = false;
+    for config in configs.iter().cloned() {
+        let state_path = state_path(&config);
+        if configs.len() > 1 {
+            let name = config.library.as_deref().unwrap_or("default");
+            events.info(format!("Syncing the {} library", name));
+        }
 
-                        sync_missing_files(
-                            &client,
-                            &config,
-                            &encrypted_token,
-                            &file_entries,
-                            &missing_files_names,
-                        )?;
-                    }
+        let mut session = SyncSession::new(config, state_path, events.clone())?
+            .with_cancel_handle(cancel.clone());
+        match session.run().await {
+            Ok(summary) if summary.failed_uploads > 0 => {
+                events.warn(format!(
+                    "{} uploads failed, they will be retried on the next run",
+                    summary.failed_uploads
+                ));
+                failed = true;
+            }
+            Ok(_) => {}
+            Err(err) => {
+                events.warn(err.to_string());
+                if matches!(err, Error::Cancelled) {
+                    std::process::exit(1);
                 }
+                failed = true;
             }
         }
-    } else {
-        eprintln!("Failed to sync files!");
     }
 
+    if failed {
+        std::process::exit(1);
+    }
     Ok(())
 }
 
-fn sync_missing_files(
-    client: &reqwest::blocking::Client,
-    config: &Config,
-    encrypted_token: &str,
-    file_entries: &cbf::FileEntries,
-    missing_files: &HashSet<String>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let missing_files = missing_files
-        .iter()
-        .map(|name| (name, file_entries.get(name).unwrap()))
-        .collect::<HashMap<_, _>>();
-
-    let mut buffer = Vec::new();
-    cbf::write(&mut buffer, &missing_files, None::<&HashSet<&String>>)?;
-
-    let response = client
-        .post(format!("{}/sync", config.server_url))
-        .header("Authorization", encrypted_token)
-        .body(buffer)
-        .send()?;
-    if response.status().is_success() && response.text()? == "synced" {
-        println!("Synced missing files!");
-    } else {
-        eprintln!("Failed to sync missing files!");
+/// Every library keeps its own state, the default one in `state.json`
+fn state_path(config: &Config) -> String {
+    match &config.library {
+        Some(library) => format!("state.{}.json", library),
+        None => STATE_PATH.to_string(),
     }
-
-    Ok(())
 }
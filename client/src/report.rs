@@ -0,0 +1,187 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sync_core::events::{Direction, Event, Level, ProgressUpdate, Summary};
+use utils::protocol::{FileVersion, SnapshotInfo, UploadResult};
+
+/// Prints an event of the sync. With `--json` every line on stdout is the JSON of an
+/// event, for programs wrapping the client.
+pub fn render(event: &Event, json: bool) {
+    if json {
+        println!("{}", serde_json::to_string(event).unwrap());
+        return;
+    }
+
+    match event {
+        Event::Message {
+            level: Level::Info,
+            message,
+        } => println!("{}", message),
+        Event::Message {
+            level: Level::Warning,
+            message,
+        } => eprintln!("{}", message),
+        Event::Progress(update) => println!("{}", progress_line(update)),
+        // only the uploads that didn't simply get written are worth a line for humans
+        Event::File {
+            name,
+            result: Some(result),
+            ..
+        } => match result {
+            UploadResult::Written => {}
+            UploadResult::Identical => println!("{}: {}", name, result),
+            UploadResult::Rejected { .. } | UploadResult::Failed { .. } => {
+                eprintln!("{}: {}", name, result)
+            }
+        },
+        Event::File { .. } => {}
+        Event::Summary(summary) => print_summary(summary),
+    }
+}
+
+fn progress_line(update: &ProgressUpdate) -> String {
+    let verb = match update.direction {
+        Direction::Download => "Downloading",
+        Direction::Upload => "Uploading",
+    };
+    let mut line = format!("{} {}", verb, format_bytes(update.bytes));
+    if let Some(total) = update.total_bytes.filter(|total| *total > 0) {
+        line += &format!(
+            " of {} ({}%)",
+            format_bytes(total),
+            update.bytes * 100 / total
+        );
+    }
+    line += &match update.total_files {
+        Some(total) => format!(", {} of {} files", update.files, total),
+        None => format!(", {} files", update.files),
+    };
+    line += &format!(", {}/s", format_bytes(update.rate as u64));
+    if let Some(eta) = update.eta {
+        line += &format!(", {} left", format_duration(eta));
+    }
+    line
+}
+
+fn print_summary(summary: &Summary) {
+    let transfers = [
+        ("Downloaded", summary.downloaded),
+        ("Uploaded", summary.uploaded),
+    ];
+    for (verb, transfer) in transfers {
+        let Some(transfer) = transfer else {
+            continue;
+        };
+        let rate = transfer.bytes as f64 / transfer.seconds.max(0.001);
+        println!(
+            "{} {} files ({}) in {}, {}/s",
+            verb,
+            transfer.files,
+            format_bytes(transfer.bytes),
+            format_duration(transfer.seconds),
+            format_bytes(rate as u64)
+        );
+    }
+}
+
+/// One version of the server's trash, with how long ago it left the library
+pub fn print_version(version: &FileVersion, json: bool) {
+    if json {
+        println!("{}", serde_json::to_string(version).unwrap());
+        return;
+    }
+
+    println!(
+        "{}  {}  {} {} ago  {}",
+        version.name,
+        version.version,
+        version.reason,
+        format_duration(age(version.version)),
+        format_bytes(version.size)
+    );
+}
+
+/// One snapshot of the server, with how long ago it was taken
+pub fn print_snapshot(snapshot: &SnapshotInfo, json: bool) {
+    if json {
+        println!("{}", serde_json::to_string(snapshot).unwrap());
+        return;
+    }
+
+    println!(
+        "{}  {} ago  {} files  {}",
+        snapshot.id,
+        format_duration(age(snapshot.id)),
+        snapshot.files,
+        format_bytes(snapshot.bytes)
+    );
+}
+
+/// Seconds since `millis`, in milliseconds since the Unix epoch
+fn age(millis: u64) -> f64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    now.saturating_sub(millis) as f64 / 1000.0
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+pub fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m {}s", seconds / 60, seconds % 60),
+        3600..=86399 => format!("{}h {}m", seconds / 3600, seconds % 3600 / 60),
+        _ => format!("{}d {}h", seconds / 86400, seconds % 86400 / 3600),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GB");
+
+        assert_eq!(format_duration(42.4), "42s");
+        assert_eq!(format_duration(125.0), "2m 5s");
+        assert_eq!(format_duration(7260.0), "2h 1m");
+        assert_eq!(format_duration(266400.0), "3d 2h");
+    }
+
+    #[test]
+    fn test_progress_line() {
+        let update = ProgressUpdate {
+            direction: Direction::Upload,
+            bytes: 512,
+            total_bytes: Some(2048),
+            files: 1,
+            total_files: Some(4),
+            rate: 256.0,
+            eta: Some(6.0),
+        };
+        assert_eq!(
+            progress_line(&update),
+            "Uploading 512 B of 2.0 KB (25%), 1 of 4 files, 256 B/s, 6s left"
+        );
+    }
+}
@@ -0,0 +1,73 @@
+use sync_core::{events::Events, snapshot, CancellationToken, Config, Error, SyncSession};
+
+use crate::report;
+
+const USAGE: &str = "Usage: client snapshots [--library NAME]\n       client restore --snapshot ID [--library NAME]";
+
+/// `snapshots` lists the snapshots the server took of the library, `restore --snapshot`
+/// brings the music directory back to one of them
+pub async fn run(
+    config: &Config,
+    args: &[String],
+    events: &Events,
+    json: bool,
+    cancel: CancellationToken,
+) -> Result<(), Error> {
+    let mut config = config.clone();
+    let mut id = None;
+    let mut args = args.iter();
+    let command = args.next().map(String::as_str);
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| Error::Config(USAGE.to_string()))?;
+        match arg.as_str() {
+            "--library" => config.library = Some(value.clone()),
+            "--snapshot" => {
+                id = Some(
+                    value
+                        .parse()
+                        .map_err(|_| Error::Config(format!("Invalid snapshot: {}", value)))?,
+                )
+            }
+            _ => return Err(Error::Config(USAGE.to_string())),
+        }
+    }
+
+    // the music directory of the library, from its `library` setting
+    if let Some(library) = &config.library {
+        config = config
+            .per_library()
+            .into_iter()
+            .find(|other| other.library.as_ref() == Some(library))
+            .ok_or_else(|| {
+                Error::Config(format!(
+                    "No library = {} ... setting in config.conf",
+                    library
+                ))
+            })?;
+    }
+
+    match (command, id) {
+        (Some("snapshots"), None) => {
+            let snapshots = snapshot::list(&config, events).await?;
+            if snapshots.is_empty() && !json {
+                events.info("The server has no snapshots of this library");
+            }
+            for snapshot in &snapshots {
+                report::print_snapshot(snapshot, json);
+            }
+            Ok(())
+        }
+        (Some("restore"), Some(_)) if config.music_dir.is_empty() => Err(Error::Config(
+            "The music directory is empty, use --library to restore a library".to_string(),
+        )),
+        (Some("restore"), Some(id)) => {
+            let state_path = crate::state_path(&config);
+            let mut session =
+                SyncSession::new(config, state_path, events.clone())?.with_cancel_handle(cancel);
+            session.restore_snapshot(id).await.map(|_| ())
+        }
+        _ => Err(Error::Config(USAGE.to_string())),
+    }
+}
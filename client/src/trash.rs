@@ -0,0 +1,60 @@
+use sync_core::{events::Events, trash, Config, Error};
+
+use crate::report;
+
+const USAGE: &str = "Usage: client trash [--library NAME] [FILE]\n       client restore [--library NAME] FILE VERSION";
+
+/// `trash` lists the versions the server keeps of replaced and deleted files, `restore`
+/// puts one of them back. Both use the default library unless `--library` names another.
+pub async fn run(
+    config: &Config,
+    args: &[String],
+    events: &Events,
+    json: bool,
+) -> Result<(), Error> {
+    let mut config = config.clone();
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    let command = args.next().map(String::as_str);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--library" => {
+                let name = args
+                    .next()
+                    .ok_or_else(|| Error::Config(USAGE.to_string()))?;
+                config.library = Some(name.clone());
+            }
+            _ => positional.push(arg.as_str()),
+        }
+    }
+
+    match (command, positional.as_slice()) {
+        (Some("trash"), []) | (Some("trash"), [_]) => {
+            let name = positional.first().copied();
+            let versions = trash::versions(&config, name, events).await?;
+            if versions.is_empty() && !json {
+                events.info("The trash is empty");
+            }
+            for version in &versions {
+                report::print_version(version, json);
+            }
+            Ok(())
+        }
+        (Some("restore"), [name, version]) => {
+            let version = version
+                .parse()
+                .map_err(|_| Error::Config(format!("Invalid version: {}", version)))?;
+            let restored = trash::restore(&config, name, version, events).await?;
+            if json {
+                report::print_version(&restored, json);
+            } else {
+                events.info(format!(
+                    "Restored {} from version {}",
+                    restored.name, restored.version
+                ));
+            }
+            Ok(())
+        }
+        _ => Err(Error::Config(USAGE.to_string())),
+    }
+}
@@ -0,0 +1,128 @@
+use std::{fmt, io};
+
+use actix_web::{
+    http::{header, StatusCode},
+    HttpResponse, ResponseError,
+};
+use utils::protocol::ErrorResponse;
+
+/// Everything a handler can fail with, sent to the client as an `ErrorResponse`
+#[derive(Debug)]
+pub enum ServerError {
+    Unauthorized,
+    /// The token can only read the library
+    Forbidden,
+    NotFound,
+    NoSuchLibrary(String),
+    InvalidManifest(io::Error),
+    InvalidUpload(io::Error),
+    InvalidQuery(&'static str),
+    /// Uploads have to announce their size so memory can be reserved up front
+    LengthRequired,
+    /// Larger than the upload limit, in bytes
+    UploadTooLarge(u64),
+    /// The upload queue is full, the client should retry after this many seconds
+    Busy(u64),
+    /// The bytes the token already uses and its `max_bytes`
+    OverQuota(u64, u64),
+    /// Moving files in or out of the trash failed
+    Io(io::Error),
+}
+
+impl ServerError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Unauthorized => "unauthorized",
+            Self::Forbidden => "forbidden",
+            Self::NotFound => "not_found",
+            Self::NoSuchLibrary(_) => "no_such_library",
+            Self::InvalidManifest(_) => "invalid_manifest",
+            Self::InvalidUpload(_) => "invalid_upload",
+            Self::InvalidQuery(_) => "invalid_query",
+            Self::LengthRequired => "length_required",
+            Self::UploadTooLarge(_) => "upload_too_large",
+            Self::Busy(_) => "busy",
+            Self::OverQuota(..) => "over_quota",
+            Self::Io(_) => "io_error",
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unauthorized => write!(f, "Missing or invalid token"),
+            Self::Forbidden => write!(f, "This token can't write to the library"),
+            Self::NotFound => write!(f, "No such file"),
+            Self::NoSuchLibrary(name) => write!(f, "No such library: {}", name),
+            Self::InvalidManifest(err) => write!(f, "Invalid manifest: {}", err),
+            Self::InvalidUpload(err) => write!(f, "Invalid upload: {}", err),
+            Self::InvalidQuery(reason) => write!(f, "Invalid query: {}", reason),
+            Self::LengthRequired => write!(f, "Uploads need a Content-Length header"),
+            Self::UploadTooLarge(limit) => {
+                write!(f, "Uploads can't be larger than {} bytes", limit)
+            }
+            Self::Busy(retry_after) => write!(
+                f,
+                "Too many uploads in progress, retry in {} seconds",
+                retry_after
+            ),
+            Self::OverQuota(used, max_bytes) => write!(
+                f,
+                "Over this token's quota, {} of {} bytes are used",
+                used, max_bytes
+            ),
+            Self::Io(err) => write!(f, "Failed to access the files: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+impl ResponseError for ServerError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::NotFound | Self::NoSuchLibrary(_) => StatusCode::NOT_FOUND,
+            Self::InvalidManifest(_) | Self::InvalidUpload(_) | Self::InvalidQuery(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            Self::LengthRequired => StatusCode::LENGTH_REQUIRED,
+            Self::UploadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::Busy(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::OverQuota(..) => StatusCode::INSUFFICIENT_STORAGE,
+            Self::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut builder = HttpResponse::build(self.status_code());
+        if let Self::Busy(retry_after) = self {
+            builder.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+        }
+
+        builder.json(ErrorResponse {
+            error: self.code().to_string(),
+            message: self.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_response() {
+        let err = ServerError::InvalidUpload(io::Error::other("truncated entry"));
+
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(err.code(), "invalid_upload");
+        assert_eq!(err.to_string(), "Invalid upload: truncated entry");
+
+        let response = ServerError::Busy(5).error_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "5");
+    }
+}
@@ -0,0 +1,259 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::http::header::{self, ContentRangeSpec, EntityTag, IfNoneMatch, Range};
+use actix_web::{delete, get, web, HttpMessage, HttpRequest, HttpResponse};
+
+use serde::Deserialize;
+use utils::protocol::TrashReason;
+
+use crate::{error::ServerError, libraries::Access, AppState};
+
+/// The route may also name a library, which isn't needed here
+#[derive(Deserialize)]
+struct FilePath {
+    path: String,
+}
+
+enum RequestedRange {
+    Full,
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Streams a single track so it can be played without syncing the whole library.
+/// Only single byte ranges are honored, multiple ranges fall back to the full file.
+#[get("/files/{path:.*}")]
+async fn file_get(
+    state: web::Data<AppState>,
+    path: web::Path<FilePath>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ServerError> {
+    let library = state.libraries.authorize(&req, Access::Read)?.library;
+
+    let name = utils::normalize_name(&path.into_inner().path);
+    let Some(entry) = library.index.get(&name) else {
+        return Err(ServerError::NotFound);
+    };
+    let data = &entry.data;
+
+    let full_length = data.len() as u64;
+    let etag = entity_tag(full_length, entry.modified);
+
+    let not_modified = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => false,
+    };
+
+    if not_modified {
+        return Ok(HttpResponse::NotModified()
+            .insert_header(header::ETag(etag))
+            .finish());
+    }
+
+    let response = match requested_range(&req, full_length) {
+        RequestedRange::Full => HttpResponse::Ok()
+            .content_type(mime_type(&name))
+            .insert_header(header::ETag(etag))
+            .insert_header((header::ACCEPT_RANGES, "bytes"))
+            .body(data.clone()),
+        RequestedRange::Partial(from, to) => HttpResponse::PartialContent()
+            .content_type(mime_type(&name))
+            .insert_header(header::ETag(etag))
+            .insert_header((header::ACCEPT_RANGES, "bytes"))
+            .insert_header(header::ContentRange(ContentRangeSpec::Bytes {
+                range: Some((from, to)),
+                instance_length: Some(full_length),
+            }))
+            .body(data[from as usize..=to as usize].to_vec()),
+        RequestedRange::Unsatisfiable => HttpResponse::RangeNotSatisfiable()
+            .insert_header(header::ContentRange(ContentRangeSpec::Bytes {
+                range: None,
+                instance_length: Some(full_length),
+            }))
+            .finish(),
+    };
+
+    Ok(response)
+}
+
+/// Takes a file out of the library and moves it to the trash, where it can be restored
+/// from. Clients that still have the file will upload it again on their next sync.
+#[delete("/files/{path:.*}")]
+async fn file_delete(
+    state: web::Data<AppState>,
+    path: web::Path<FilePath>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ServerError> {
+    let authorized = state.libraries.authorize(&req, Access::Write)?;
+    let library = authorized.library;
+
+    let name = utils::normalize_name(&path.into_inner().path);
+    let _lock = library.index.lock_path(&name).await;
+    if !library.index.contains(&name) {
+        return Err(ServerError::NotFound);
+    }
+    let path = library.path(&name).ok_or(ServerError::NotFound)?;
+
+    state
+        .trash
+        .keep(&library.name, &name, &path, TrashReason::Deleted)
+        .await
+        .map_err(ServerError::Io)?;
+    library.index.remove(&name);
+
+    // the bytes go back to the token that wrote the file
+    if state.usage.owner(&library.name, &name).is_some() {
+        state.usage.try_set_owner(&library.name, &name, None, None);
+        if let Err(err) = state.usage.save().await {
+            eprintln!("Failed to save the usage of the tokens: {}", err);
+        }
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+fn requested_range(req: &HttpRequest, full_length: u64) -> RequestedRange {
+    match req.get_header::<Range>() {
+        Some(Range::Bytes(ranges)) if ranges.len() == 1 => {
+            match ranges[0].to_satisfiable_range(full_length) {
+                Some((from, to)) => RequestedRange::Partial(from, to),
+                None => RequestedRange::Unsatisfiable,
+            }
+        }
+        _ => RequestedRange::Full,
+    }
+}
+
+/// Same idea as what most static file servers do, the size and modification time
+/// change whenever a client uploads a new version of the file
+fn entity_tag(length: u64, modified: SystemTime) -> EntityTag {
+    let modified = modified
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or(0);
+
+    EntityTag::new_strong(format!("{:x}-{:x}", length, modified))
+}
+
+/// The lowercase extension of `name`, empty if it has none
+pub fn extension(name: &str) -> String {
+    name.rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .unwrap_or_default()
+}
+
+pub fn mime_type(name: &str) -> &'static str {
+    match extension(name).as_str() {
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" | "oga" => "audio/ogg",
+        "opus" => "audio/opus",
+        "m4a" | "m4b" | "mp4" => "audio/mp4",
+        "aac" => "audio/aac",
+        "wav" => "audio/wav",
+        "aif" | "aiff" => "audio/aiff",
+        "wma" => "audio/x-ms-wma",
+        "webm" => "audio/webm",
+        "m3u" | "m3u8" => "audio/x-mpegurl",
+        "pls" => "audio/x-scpls",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_mime_type() {
+        assert_eq!(mime_type("song.MP3"), "audio/mpeg");
+        assert_eq!(mime_type("song.flac"), "audio/flac");
+        assert_eq!(mime_type("no_extension"), "application/octet-stream");
+    }
+
+    #[test]
+    fn test_requested_range() {
+        let req = TestRequest::default()
+            .insert_header((header::RANGE, "bytes=10-19"))
+            .to_http_request();
+        assert!(matches!(
+            requested_range(&req, 100),
+            RequestedRange::Partial(10, 19)
+        ));
+
+        let req = TestRequest::default()
+            .insert_header((header::RANGE, "bytes=-10"))
+            .to_http_request();
+        assert!(matches!(
+            requested_range(&req, 100),
+            RequestedRange::Partial(90, 99)
+        ));
+
+        let req = TestRequest::default()
+            .insert_header((header::RANGE, "bytes=200-"))
+            .to_http_request();
+        assert!(matches!(
+            requested_range(&req, 100),
+            RequestedRange::Unsatisfiable
+        ));
+
+        let req = TestRequest::default().to_http_request();
+        assert!(matches!(requested_range(&req, 100), RequestedRange::Full));
+    }
+
+    #[actix_web::test]
+    async fn test_nfd_names() {
+        use crate::{libraries::Grant, sniff::mp3, upload_body, AppState};
+        use actix_web::{test, App};
+        use utils::encryption::TokenVerifier;
+
+        let root = std::env::temp_dir().join(format!("music_sync_nfd_{}", std::process::id()));
+        let dir = root.join("music");
+        std::fs::create_dir_all(&dir).unwrap();
+        // written by a Mac, the index knows it as "Café.mp3"
+        let nfd = dir.join("Cafe\u{301}.mp3");
+        std::fs::write(&nfd, mp3(b"nfd")).unwrap();
+
+        let state = AppState::for_tests(&root, &["music"], vec![Grant::all("admin")]);
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(crate::sync_post)
+                .service(file_get)
+                .service(file_delete),
+        )
+        .await;
+        let authorization = TokenVerifier::new("admin").encrypt(b"admin");
+
+        // replacing it writes over the NFD file instead of next to it
+        let req = test::TestRequest::post()
+            .uri("/sync")
+            .insert_header(("Authorization", authorization.clone()))
+            .set_payload(upload_body(&[("Caf\u{e9}.mp3", &mp3(b"nfc"))]))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        assert_eq!(std::fs::read(&nfd).unwrap(), mp3(b"nfc"));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        // it's found whatever the normalization of the requested name
+        let req = test::TestRequest::get()
+            .uri("/files/Cafe%CC%81.mp3")
+            .insert_header(("Authorization", authorization.clone()))
+            .to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, mp3(b"nfc"));
+
+        let req = test::TestRequest::delete()
+            .uri("/files/Caf%C3%A9.mp3")
+            .insert_header(("Authorization", authorization))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::NO_CONTENT);
+        assert!(!nfd.exists());
+        let versions = state.trash.versions("music", None).await.unwrap();
+        assert_eq!(versions.len(), 2);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::hash::{BuildHasher, RandomState};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use tokio::sync::{Mutex, MutexGuard};
+use utils::tags::TrackInfo;
+
+const SHARDS: usize = 16;
+const WRITE_LOCKS: usize = 64;
+
+type Shard = RwLock<HashMap<String, Arc<Entry>>>;
+
+/// A file of the library
+pub struct Entry {
+    /// The file's name in the library directory, which may not be the NFC name it's
+    /// indexed under
+    pub disk_name: OsString,
+    pub data: Vec<u8>,
+    /// When the file was added or last replaced
+    pub modified: SystemTime,
+    /// `None` for playlists, they aren't part of the catalog
+    pub info: Option<TrackInfo>,
+}
+
+impl Entry {
+    /// The file `name` as it was read from or written to `path`
+    pub fn new(name: &str, path: &Path, data: Vec<u8>, modified: SystemTime) -> Self {
+        let info = (!utils::playlist::is_playlist(name)).then(|| utils::tags::parse(&data));
+
+        Self {
+            disk_name: path.file_name().unwrap_or(name.as_ref()).to_owned(),
+            data,
+            modified,
+            info,
+        }
+    }
+}
+
+/// Every file of the library, split in shards so that readers only ever wait for
+/// the insertions in the same shard, and only for as long as a map insertion takes.
+/// Entries are handed out as `Arc`s so nothing is held locked while they're used.
+pub struct Index {
+    hasher: RandomState,
+    shards: Box<[Shard]>,
+    /// Serializes the writes to a path. Paths share these locks, which costs some
+    /// parallelism on collisions but keeps the memory use fixed.
+    write_locks: Box<[Mutex<()>]>,
+}
+
+impl Index {
+    pub fn new() -> Self {
+        Self {
+            hasher: RandomState::new(),
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+            write_locks: (0..WRITE_LOCKS).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    fn slot(&self, name: &str, slots: usize) -> usize {
+        (self.hasher.hash_one(name) % slots as u64) as usize
+    }
+
+    fn shard(&self, name: &str) -> &Shard {
+        &self.shards[self.slot(name, SHARDS)]
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Entry>> {
+        self.shard(name).read().unwrap().get(name).cloned()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.shard(name).read().unwrap().contains_key(name)
+    }
+
+    pub fn insert(&self, name: String, entry: Entry) {
+        self.shard(&name)
+            .write()
+            .unwrap()
+            .insert(name, Arc::new(entry));
+    }
+
+    pub fn remove(&self, name: &str) -> Option<Arc<Entry>> {
+        self.shard(name).write().unwrap().remove(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().len())
+            .sum()
+    }
+
+    /// Every entry, each shard is copied on its own so insertions made meanwhile may or
+    /// may not be included
+    pub fn snapshot(&self) -> Vec<(String, Arc<Entry>)> {
+        let mut entries = Vec::with_capacity(self.len());
+        for shard in self.shards.iter() {
+            let shard = shard.read().unwrap();
+            entries.extend(
+                shard
+                    .iter()
+                    .map(|(name, entry)| (name.clone(), entry.clone())),
+            );
+        }
+        entries
+    }
+
+    /// Has to be held while writing `name` to disk and updating its entry, so that two
+    /// uploads of the same file can't interleave
+    pub async fn lock_path(&self, name: &str) -> MutexGuard<'_, ()> {
+        self.write_locks[self.slot(name, WRITE_LOCKS)].lock().await
+    }
+}
+
+impl FromIterator<(String, Entry)> for Index {
+    fn from_iter<I: IntoIterator<Item = (String, Entry)>>(entries: I) -> Self {
+        let index = Self::new();
+        for (name, entry) in entries {
+            index.insert(name, entry);
+        }
+        index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrent_inserts_and_reads() {
+        let index = Arc::new(Index::new());
+
+        let writers = (0..8).map(|writer| {
+            let index = index.clone();
+            std::thread::spawn(move || {
+                for i in 0..500 {
+                    let name = format!("{}-{}.mp3", writer, i);
+                    index.insert(
+                        name.clone(),
+                        Entry::new(&name, Path::new(&name), vec![writer], SystemTime::now()),
+                    );
+                }
+            })
+        });
+        let readers = (0..8).map(|_| {
+            let index = index.clone();
+            std::thread::spawn(move || {
+                for _ in 0..50 {
+                    for (name, entry) in index.snapshot() {
+                        let writer: u8 = name.split('-').next().unwrap().parse().unwrap();
+                        assert_eq!(entry.data, vec![writer]);
+                    }
+                }
+            })
+        });
+
+        let threads: Vec<_> = writers.chain(readers).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(index.len(), 8 * 500);
+        assert!(index.get("3-499.mp3").is_some());
+        assert!(index.get("list.m3u").is_none());
+        assert!(index.get("7-0.mp3").unwrap().info.is_some());
+    }
+}
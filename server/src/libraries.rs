@@ -0,0 +1,325 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use serde::Serialize;
+use utils::{
+    encryption::TokenVerifier,
+    file_names::{self, NonUtf8Names},
+    protocol,
+};
+
+use crate::{error::ServerError, index::Entry, index::Index, quota::Quota, AppState};
+
+/// The library of the `music_dir` line, served on the routes without a library name
+pub const DEFAULT_LIBRARY: &str = "music";
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// A named directory the server hosts, with its own index
+pub struct Library {
+    pub name: String,
+    pub dir: String,
+    pub index: Index,
+}
+
+impl Library {
+    pub fn load(name: &str, dir: &str, non_utf8_names: NonUtf8Names) -> io::Result<Self> {
+        let (mut disk_names, file_entries) = utils::get_files(dir, non_utf8_names)?;
+
+        let index = file_entries
+            .into_iter()
+            .map(|(name, data)| {
+                let path = Path::new(dir).join(disk_names.remove(&name).unwrap_or_default());
+                let modified = fs::metadata(&path)
+                    .and_then(|metadata| metadata.modified())
+                    .unwrap_or(UNIX_EPOCH);
+                let entry = Entry::new(&name, &path, data, modified);
+                (name, entry)
+            })
+            .collect();
+
+        Ok(Self {
+            name: name.to_string(),
+            dir: dir.to_string(),
+            index,
+        })
+    }
+
+    /// Where the file called `name` is, or would be written: the name it has on disk
+    /// when it's in the index, otherwise `name` itself if it's a valid file name
+    pub fn path(&self, name: &str) -> Option<PathBuf> {
+        match self.index.get(name) {
+            Some(entry) => Some(Path::new(&self.dir).join(&entry.disk_name)),
+            None => file_names::path(&self.dir, name),
+        }
+    }
+}
+
+/// What a `token = ...` setting grants, e.g.
+/// `token = abc123 music podcasts:read name=phone max_bytes=10G extensions=mp3,flac`.
+/// The token of the first config line can write to every library without limits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grant {
+    pub token: String,
+    /// `None` gives write access to every library
+    pub libraries: Option<HashMap<String, Access>>,
+    /// What the token's usage is saved as, needed for `max_bytes`
+    pub name: Option<String>,
+    pub quota: Quota,
+}
+
+impl Grant {
+    pub fn all(token: &str) -> Self {
+        Self {
+            token: token.to_string(),
+            libraries: None,
+            name: None,
+            quota: Quota::default(),
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut words = value.split_whitespace();
+        let token = words.next()?.to_string();
+
+        let mut libraries = HashMap::new();
+        let mut name = None;
+        let mut quota = Quota::default();
+        for word in words {
+            if let Some((key, value)) = word.split_once('=') {
+                match key {
+                    "name" => name = Some(value.to_string()),
+                    _ if quota.set(key, value) => {}
+                    _ => return None,
+                }
+                continue;
+            }
+
+            let (name, access) = match word.split_once(':') {
+                Some((name, "read")) => (name, Access::Read),
+                Some((name, "write")) => (name, Access::Write),
+                Some(_) => return None,
+                None => (word, Access::Write),
+            };
+            if !protocol::is_valid_library_name(name) {
+                return None;
+            }
+            libraries.insert(name.to_string(), access);
+        }
+
+        // the usage of unnamed tokens isn't kept, so they can't have a total
+        if libraries.is_empty() || (quota.max_bytes.is_some() && name.is_none()) {
+            return None;
+        }
+
+        Some(Self {
+            token,
+            libraries: Some(libraries),
+            name,
+            quota,
+        })
+    }
+
+    pub fn access(&self, library: &str) -> Option<Access> {
+        match &self.libraries {
+            Some(libraries) => libraries.get(library).copied(),
+            None => Some(Access::Write),
+        }
+    }
+}
+
+/// A request whose token may use the library
+pub struct Authorized<'a> {
+    pub library: &'a Library,
+    pub grant: &'a Grant,
+    /// What the token may really do, which can be more than what was asked
+    pub access: Access,
+}
+
+/// Every library of the server and the tokens that may use them
+pub struct Libraries {
+    libraries: BTreeMap<String, Library>,
+    tokens: Vec<(TokenVerifier, Grant)>,
+}
+
+impl Libraries {
+    pub fn new(libraries: impl IntoIterator<Item = Library>, grants: Vec<Grant>) -> Self {
+        Self {
+            libraries: libraries
+                .into_iter()
+                .map(|library| (library.name.clone(), library))
+                .collect(),
+            tokens: grants
+                .into_iter()
+                .map(|grant| (TokenVerifier::new(&grant.token), grant))
+                .collect(),
+        }
+    }
+
+    /// The grant of the token in the `Authorization` header
+    pub fn grant(&self, req: &HttpRequest) -> Result<&Grant, ServerError> {
+        let header = req
+            .headers()
+            .get("Authorization")
+            .and_then(|value| value.to_str().ok())
+            .ok_or(ServerError::Unauthorized)?;
+
+        self.tokens
+            .iter()
+            .find(|(verifier, _)| {
+                verifier
+                    .decrypt(header)
+                    .is_some_and(|token| verifier.verify(&token))
+            })
+            .map(|(_, grant)| grant)
+            .ok_or(ServerError::Unauthorized)
+    }
+
+    /// The library named by the route, or the default one on the routes without a name,
+    /// if the token may use it with `access`
+    pub fn authorize(
+        &self,
+        req: &HttpRequest,
+        access: Access,
+    ) -> Result<Authorized<'_>, ServerError> {
+        let grant = self.grant(req)?;
+        let name = req.match_info().get("library").unwrap_or(DEFAULT_LIBRARY);
+
+        // libraries the token can't use are answered like missing ones
+        let (Some(library), Some(granted)) = (self.libraries.get(name), grant.access(name)) else {
+            return Err(ServerError::NoSuchLibrary(name.to_string()));
+        };
+        if granted < access {
+            return Err(ServerError::Forbidden);
+        }
+
+        Ok(Authorized {
+            library,
+            grant,
+            access: granted,
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Library> {
+        self.libraries.values()
+    }
+}
+
+#[derive(Serialize)]
+struct LibraryInfo<'a> {
+    name: &'a str,
+    files: usize,
+    access: Access,
+}
+
+/// The libraries the token can use
+#[get("/libraries")]
+async fn libraries_get(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ServerError> {
+    let grant = state.libraries.grant(&req)?;
+
+    let libraries = state
+        .libraries
+        .iter()
+        .filter_map(|library| {
+            Some(LibraryInfo {
+                name: &library.name,
+                files: library.index.len(),
+                access: grant.access(&library.name)?,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(libraries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_grant() {
+        let grant = Grant::parse("secret music podcasts:read").unwrap();
+        assert_eq!(grant.token, "secret");
+        assert_eq!(grant.access("music"), Some(Access::Write));
+        assert_eq!(grant.access("podcasts"), Some(Access::Read));
+        assert_eq!(grant.access("audiobooks"), None);
+        assert_eq!(
+            Grant::all("secret").access("audiobooks"),
+            Some(Access::Write)
+        );
+
+        let grant = Grant::parse("secret music name=phone max_bytes=1G extensions=mp3").unwrap();
+        assert_eq!(grant.name.as_deref(), Some("phone"));
+        assert_eq!(grant.quota.max_bytes, Some(1024 * 1024 * 1024));
+
+        assert_eq!(Grant::parse("secret"), None);
+        assert_eq!(Grant::parse("secret music max_bytes=1G"), None);
+        assert_eq!(Grant::parse("secret music max_size=1G"), None);
+        assert_eq!(Grant::parse("secret music:admin"), None);
+        assert_eq!(Grant::parse("secret ../music"), None);
+    }
+
+    #[test]
+    fn test_authorize() {
+        let library = |name: &str| Library {
+            name: name.to_string(),
+            dir: String::new(),
+            index: Index::new(),
+        };
+        let libraries = Libraries::new(
+            [library("music"), library("podcasts")],
+            vec![
+                Grant::all("admin"),
+                Grant::parse("guest podcasts:read").unwrap(),
+            ],
+        );
+        let request = |token: &str, library: Option<&str>| {
+            let verifier = TokenVerifier::new(token);
+            let mut req = TestRequest::default()
+                .insert_header(("Authorization", verifier.encrypt(token.as_bytes())));
+            if let Some(library) = library {
+                req = req.param("library", library.to_string());
+            }
+            req.to_http_request()
+        };
+
+        let req = request("admin", None);
+        let authorized = libraries.authorize(&req, Access::Write).unwrap();
+        assert_eq!(authorized.library.name, "music");
+        assert_eq!(authorized.access, Access::Write);
+
+        let req = request("guest", Some("podcasts"));
+        let authorized = libraries.authorize(&req, Access::Read).unwrap();
+        assert_eq!(authorized.library.name, "podcasts");
+        assert_eq!(authorized.access, Access::Read);
+        assert!(matches!(
+            libraries.authorize(&req, Access::Write),
+            Err(ServerError::Forbidden)
+        ));
+
+        let req = request("guest", None);
+        assert!(matches!(
+            libraries.authorize(&req, Access::Read),
+            Err(ServerError::NoSuchLibrary(_))
+        ));
+
+        let req = request("intruder", None);
+        assert!(matches!(
+            libraries.authorize(&req, Access::Read),
+            Err(ServerError::Unauthorized)
+        ));
+    }
+}
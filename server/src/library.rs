@@ -0,0 +1,117 @@
+use std::time::UNIX_EPOCH;
+
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use utils::tags::TrackInfo;
+
+use crate::{error::ServerError, index::Entry, libraries::Access, AppState};
+
+pub const DEFAULT_LIMIT: usize = 100;
+pub const MAX_LIMIT: usize = 1000;
+
+#[derive(Deserialize)]
+struct LibraryQuery {
+    offset: Option<usize>,
+    limit: Option<usize>,
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    genre: Option<String>,
+    year: Option<u32>,
+}
+
+impl LibraryQuery {
+    /// Text filters are case insensitive substring matches, the year has to be exact
+    fn matches(&self, info: &TrackInfo) -> bool {
+        let text_filters = [
+            ("title", &self.title),
+            ("artist", &self.artist),
+            ("album", &self.album),
+            ("genre", &self.genre),
+        ];
+
+        let text_matches = text_filters.iter().all(|(field, filter)| match filter {
+            Some(filter) => info
+                .field(field)
+                .is_some_and(|value| value.to_lowercase().contains(&filter.to_lowercase())),
+            None => true,
+        });
+
+        text_matches && self.year.is_none_or(|year| info.year == Some(year))
+    }
+}
+
+#[derive(Serialize)]
+pub struct LibraryTrack<'a> {
+    pub name: &'a str,
+    pub size: usize,
+    /// Seconds since the unix epoch of when the file was added or last replaced
+    pub modified: u64,
+    #[serde(flatten)]
+    pub info: &'a TrackInfo,
+}
+
+impl<'a> LibraryTrack<'a> {
+    /// `None` for the entries that aren't part of the catalog, like playlists
+    pub fn new(name: &'a str, entry: &'a Entry) -> Option<Self> {
+        let modified = entry
+            .modified
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+
+        Some(Self {
+            name,
+            size: entry.data.len(),
+            modified,
+            info: entry.info.as_ref()?,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct LibraryPage<'a> {
+    total: usize,
+    offset: usize,
+    limit: usize,
+    tracks: Vec<LibraryTrack<'a>>,
+}
+
+/// Paginated listing of the catalog, ordered by artist, album and track number so
+/// that pages stay stable between requests
+#[get("/library")]
+async fn library_get(
+    state: web::Data<AppState>,
+    query: web::Query<LibraryQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ServerError> {
+    let library = state.libraries.authorize(&req, Access::Read)?.library;
+
+    let snapshot = library.index.snapshot();
+    let mut tracks = snapshot
+        .iter()
+        .filter_map(|(name, entry)| LibraryTrack::new(name, entry))
+        .filter(|track| query.matches(track.info))
+        .collect::<Vec<_>>();
+
+    tracks.sort_by(|a, b| {
+        (&a.info.artist, &a.info.album, a.info.track_number, a.name).cmp(&(
+            &b.info.artist,
+            &b.info.album,
+            b.info.track_number,
+            b.name,
+        ))
+    });
+
+    let total = tracks.len();
+    let offset = query.offset.unwrap_or(0);
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+
+    let tracks = tracks.into_iter().skip(offset).take(limit).collect();
+
+    Ok(HttpResponse::Ok().json(LibraryPage {
+        total,
+        offset,
+        limit,
+        tracks,
+    }))
+}
@@ -7,25 +7,88 @@ static GLOBAL: MiMalloc = MiMalloc;
 
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
 use std::io;
+use std::net::IpAddr;
 use std::sync::Arc;
-
-use actix_web::{get, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
-use tokio::sync::RwLock;
-use utils::{cbf, encryption::TokenVerifier, split_strings::SplitStrings};
-
+use std::time::SystemTime;
+
+use actix_web::{
+    get, http::header, post, web, App, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer,
+};
+use error::ServerError;
+use index::Entry;
+use libraries::{Access, Grant, Libraries, Library, DEFAULT_LIBRARY};
+use queue::UploadQueue;
+use quota::{Owner, Usage};
+use snapshot::Snapshots;
+use sniff::Allowlist;
+use throttle::Throttle;
+use trash::{Retention, Trash};
+use utils::{
+    bandwidth::Schedule,
+    cbf,
+    file_names::{self, NonUtf8Names},
+    protocol::{self, Manifest, ServerInfo, SyncResponse, SyncStatus, TrashReason, UploadResult},
+};
+
+mod error;
+mod files;
+mod index;
+mod libraries;
+mod library;
+mod queue;
+mod quota;
+mod search;
+mod snapshot;
+mod sniff;
+mod throttle;
+mod trash;
+
+const USAGE_PATH: &str = "usage.json";
+
+/// Shared by every worker, the indexes do their own locking
 struct AppState {
-    file_names: HashSet<String>,
-    file_entries: cbf::FileEntries,
-    config: Config,
-    token_verifier: TokenVerifier,
+    libraries: Libraries,
+    uploads: UploadQueue,
+    /// What the tokens with a name have uploaded
+    usage: Usage,
+    allowlist: Allowlist,
+    /// Where replaced and deleted files go
+    trash: Trash,
+    snapshots: Snapshots,
+    /// Responses to the clients
+    download_limit: Arc<Throttle>,
+    /// Uploads from the clients
+    upload_limit: Arc<Throttle>,
+}
+
+impl AppState {
+    fn server_info(&self) -> ServerInfo {
+        let mut info = ServerInfo::new(env!("CARGO_PKG_VERSION"));
+        info.max_upload_bytes = Some(self.uploads.max_upload_bytes());
+        info
+    }
 }
 
 struct Config {
     token: String,
     music_dir: String,
+    /// The libraries besides the default one, by name
+    libraries: BTreeMap<String, String>,
+    /// The tokens besides the one of the first line
+    grants: Vec<Grant>,
     port: u16,
+    non_utf8_names: NonUtf8Names,
+    allowlist: Allowlist,
+    max_concurrent_uploads: usize,
+    max_upload_memory: u64,
+    download_limit: Schedule,
+    upload_limit: Schedule,
+    trash_dir: String,
+    retention: Retention,
+    snapshot_dir: String,
 }
 
 impl Config {
@@ -48,135 +111,759 @@ impl Config {
             .parse()
             .expect("Invalid port");
 
+        // everything after the first three lines is an optional `key = value` setting
+        let mut libraries = BTreeMap::new();
+        let mut grants = Vec::new();
+        let mut non_utf8_names = NonUtf8Names::default();
+        let mut allowlist = Allowlist::default();
+        let mut max_concurrent_uploads = queue::DEFAULT_MAX_CONCURRENT_UPLOADS;
+        let mut max_upload_memory = queue::DEFAULT_MAX_UPLOAD_MEMORY;
+        let mut download_limit = Schedule::default();
+        let mut upload_limit = Schedule::default();
+        let mut trash_dir = trash::DEFAULT_TRASH_DIR.to_string();
+        let mut retention = Retention::default();
+        let mut snapshot_dir = snapshot::DEFAULT_SNAPSHOT_DIR.to_string();
+
+        for line in lines {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                eprintln!("Ignoring invalid config line: {}", line);
+                continue;
+            };
+
+            let valid = match key.trim() {
+                "library" => match value.trim().split_once(char::is_whitespace) {
+                    Some((name, dir))
+                        if protocol::is_valid_library_name(name)
+                            && name != DEFAULT_LIBRARY
+                            && !libraries.contains_key(name) =>
+                    {
+                        libraries.insert(name.to_string(), dir.trim().to_string());
+                        true
+                    }
+                    _ => false,
+                },
+                "token" => Grant::parse(value)
+                    .map(|grant| grants.push(grant))
+                    .is_some(),
+                "non_utf8_names" => NonUtf8Names::parse(value.trim())
+                    .map(|value| non_utf8_names = value)
+                    .is_some(),
+                "allowed_types" => allowlist.set_types(value),
+                "allow_sidecars" => value
+                    .trim()
+                    .parse()
+                    .map(|value| allowlist.sidecars = value)
+                    .is_ok(),
+                "max_concurrent_uploads" => match value.trim().parse() {
+                    Ok(value) if value > 0 => {
+                        max_concurrent_uploads = value;
+                        true
+                    }
+                    _ => false,
+                },
+                "max_upload_memory" => match utils::parse_size(value.trim()) {
+                    Some(value) if value >= queue::MIN_MAX_UPLOAD_MEMORY => {
+                        max_upload_memory = value;
+                        true
+                    }
+                    Some(_) => {
+                        eprintln!(
+                            "max_upload_memory has to be at least {} bytes, an upload reserves twice its size",
+                            queue::MIN_MAX_UPLOAD_MEMORY
+                        );
+                        false
+                    }
+                    None => false,
+                },
+                "download_limit" => Schedule::parse(value.trim())
+                    .map(|value| download_limit = value)
+                    .is_some(),
+                "upload_limit" => Schedule::parse(value.trim())
+                    .map(|value| upload_limit = value)
+                    .is_some(),
+                "trash_dir" => {
+                    trash_dir = value.trim().to_string();
+                    !trash_dir.is_empty()
+                }
+                "snapshot_dir" => {
+                    snapshot_dir = value.trim().to_string();
+                    !snapshot_dir.is_empty()
+                }
+                "trash_versions" => value
+                    .trim()
+                    .parse()
+                    .map(|value| retention.versions = value)
+                    .is_ok(),
+                // 0 keeps the versions for as long as there aren't too many
+                "trash_days" => value
+                    .trim()
+                    .parse()
+                    .map(|value| retention.days = (value > 0).then_some(value))
+                    .is_ok(),
+                _ => false,
+            };
+
+            if !valid {
+                eprintln!("Ignoring unknown or invalid setting: {}", line);
+            }
+        }
+
+        for grant in &grants {
+            let unknown = grant.libraries.iter().flatten().filter(|(name, _)| {
+                *name != DEFAULT_LIBRARY && !libraries.contains_key(name.as_str())
+            });
+            for (name, _) in unknown {
+                eprintln!("A token names the library {:?}, which doesn't exist", name);
+            }
+        }
+
         Ok(Self {
             token,
             music_dir,
+            libraries,
+            grants,
             port,
+            non_utf8_names,
+            allowlist,
+            max_concurrent_uploads,
+            max_upload_memory,
+            download_limit,
+            upload_limit,
+            trash_dir,
+            retention,
+            snapshot_dir,
         })
     }
 }
 
-fn validate_token(req: &HttpRequest, token_verifier: &TokenVerifier) -> bool {
-    req.headers()
-        .get("Authorization")
-        .and_then(|header_value| {
-            header_value.to_str().ok().and_then(|token| {
-                token_verifier
-                    .decrypt(token)
-                    .filter(|decrypted_token| token_verifier.verify(decrypted_token))
-            })
-        })
-        .is_some()
+fn envelope(mut builder: HttpResponseBuilder, body: Vec<u8>) -> HttpResponse {
+    builder
+        .content_type(protocol::ENVELOPE_CONTENT_TYPE)
+        .body(body)
 }
 
-#[get("/sync")]
-async fn sync_get(
-    state: web::Data<Arc<RwLock<AppState>>>,
-    req_body: String,
-    req: HttpRequest,
-) -> impl Responder {
-    let state = state.read().await;
-
-    if !validate_token(&req, &state.token_verifier) {
-        return HttpResponse::Unauthorized().finish();
+/// An envelope sent as fast as the download limit of `device` allows
+fn throttled_envelope(
+    state: &AppState,
+    device: IpAddr,
+    mut builder: HttpResponseBuilder,
+    body: Vec<u8>,
+) -> HttpResponse {
+    if state.download_limit.is_unlimited() {
+        return envelope(builder, body);
     }
 
-    let incoming_files: HashSet<String> = SplitStrings::new(&req_body, '|').collect();
-    let missing: HashSet<&String> = incoming_files.difference(&state.file_names).collect(); // files that are in the client's request but not in the server's files
-    let extra: HashSet<&String> = state.file_names.difference(&incoming_files).collect(); // files that are in the server's files but not in the client's request
+    builder
+        .content_type(protocol::ENVELOPE_CONTENT_TYPE)
+        .no_chunking(body.len() as u64)
+        .streaming(state.download_limit.clone().stream(device, body))
+}
 
-    if !extra.is_empty() {
-        let extra_files = extra
+#[get("/sync")]
+async fn sync_get(
+    state: web::Data<AppState>,
+    req_body: web::Bytes,
+    req: HttpRequest,
+) -> Result<HttpResponse, ServerError> {
+    let authorized = state.libraries.authorize(&req, Access::Read)?;
+    let library = authorized.library;
+
+    let manifest = Manifest::from_json(&req_body).map_err(ServerError::InvalidManifest)?;
+
+    let Some(protocol) = protocol::negotiate(manifest.protocol) else {
+        let response = SyncResponse::new(
+            protocol::PROTOCOL_VERSION,
+            SyncStatus::Incompatible,
+            state.server_info(),
+        );
+        return Ok(envelope(HttpResponse::BadRequest(), response.to_bytes()));
+    };
+
+    let incoming_files: HashSet<String> = manifest
+        .files
+        .iter()
+        .map(|name| utils::normalize_name(name))
+        .collect();
+    let skipped: HashSet<String> = manifest
+        .skipped
+        .iter()
+        .map(|name| utils::normalize_name(name))
+        .collect();
+
+    let snapshot = library.index.snapshot();
+
+    // files that are in the client's request but not in the server's files. Nothing is
+    // asked from the clients whose token can only read the library, and only the files
+    // with an extension the token may upload from the others.
+    let quota = &authorized.grant.quota;
+    let missing = match authorized.access {
+        Access::Write => incoming_files
             .iter()
-            .map(|name| (*name, state.file_entries.get(*name).unwrap()))
-            .collect::<HashMap<_, _>>();
+            .filter(|name| !library.index.contains(name))
+            .filter(|name| state.allowlist.allows_name(name))
+            .filter(|name| quota.check_file(name, 0).is_ok())
+            .cloned()
+            .collect(),
+        Access::Read => BTreeSet::new(),
+    };
+
+    // files that are in the server's files but not in the client's request.
+    // The client only lists the files that pass its filter, so the same filter has to be
+    // applied to the server's files or the excluded ones would be sent as extra.
+    let extra_files = snapshot
+        .iter()
+        .filter(|(name, _)| !incoming_files.contains(name) && !skipped.contains(name))
+        .filter(|(name, entry)| manifest.filter.matches(name, entry.info.as_ref()))
+        .map(|(name, entry)| (name, &entry.data))
+        .collect::<HashMap<_, _>>();
+
+    let mut response = SyncResponse::new(protocol, SyncStatus::Synced, state.server_info());
+    response.missing = missing;
+    if !extra_files.is_empty() {
+        response.status = SyncStatus::Extra;
+    } else if !response.missing.is_empty() {
+        response.status = SyncStatus::Missing;
+    }
 
-        let mut buffer = Vec::new();
-        cbf::write(&mut buffer, &extra_files, Some(&missing)).unwrap();
+    let mut buffer = Vec::new();
+    response.write_with(&mut buffer, &extra_files).unwrap();
 
-        return HttpResponse::Ok()
-            .content_type("application/octet-stream")
-            .body(buffer);
-    }
-    if !missing.is_empty() {
-        let response = utils::join_hashset(&missing, '|');
+    let device = throttle::device(&req);
+    Ok(throttled_envelope(
+        &state,
+        device,
+        HttpResponse::Ok(),
+        buffer,
+    ))
+}
 
-        return HttpResponse::Ok().body(response);
+/// Reads a request body of the announced `length`, as fast as the upload limit of
+/// `device` allows
+async fn read_payload(
+    mut payload: web::Payload,
+    length: u64,
+    throttle: &Throttle,
+    device: IpAddr,
+) -> Result<Vec<u8>, ServerError> {
+    let invalid = |message: String| ServerError::InvalidUpload(io::Error::other(message));
+
+    let mut body = Vec::with_capacity(length as usize);
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|err| invalid(err.to_string()))?;
+        if body.len() + chunk.len() > length as usize {
+            return Err(invalid("longer than its Content-Length".to_string()));
+        }
+        body.extend_from_slice(&chunk);
+        throttle.wait(device, chunk.len() as u64).await;
     }
 
-    HttpResponse::Ok().body("synced")
+    Ok(body)
 }
 
 #[post("/sync")]
 async fn sync_post(
-    state: web::Data<Arc<RwLock<AppState>>>,
-    req_body: web::Bytes,
+    state: web::Data<AppState>,
+    payload: web::Payload,
     req: HttpRequest,
-) -> impl Responder {
-    if !validate_token(&req, &state.read().await.token_verifier) {
-        return HttpResponse::Unauthorized().finish();
-    }
-
-    actix_web::rt::spawn(async move {
-        let mut cursor = std::io::Cursor::new(req_body.as_ref());
-
-        let (_, entries) = cbf::read(&mut cursor).unwrap();
-
-        let mut write_tasks = FuturesUnordered::new();
-
-        // acquiring this lock here could be a bottleneck if it takes a long time to write the files and there are many requests
-        // but at the same time, we don't want to acquire the lock for each file write
-        // for my use case, this is fine
-        let mut state = state.write().await;
-
-        let music_dir = state.config.music_dir.clone();
-
-        for (name, data) in entries.into_iter() {
-            // do this to avoid cloning the data
-            let data_ptr = std::ptr::NonNull::new(&data as *const Vec<u8> as *mut Vec<u8>).unwrap();
-            let data_ref = unsafe { &*data_ptr.as_ptr() };
+) -> Result<HttpResponse, ServerError> {
+    let authorized = state.libraries.authorize(&req, Access::Write)?;
+    let (library, grant) = (authorized.library, authorized.grant);
+
+    // memory is reserved before the body is read, for the body and the parsed files
+    // that are both held for a moment
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok())
+        .ok_or(ServerError::LengthRequired)?;
+    let _permit = state.uploads.try_reserve(length)?;
+
+    // the whole upload is parsed before anything is written, a broken one is rejected
+    let device = throttle::device(&req);
+    let body = read_payload(payload, length, &state.upload_limit, device).await?;
+    let (_, entries) = cbf::read(&mut body.as_slice()).map_err(ServerError::InvalidUpload)?;
+    drop(body);
+
+    let index = &library.index;
+    let usage = &state.usage;
+    let trash = &state.trash;
+    let mut uploads = BTreeMap::new();
+    let mut write_tasks = FuturesUnordered::new();
+
+    for (name, data) in entries {
+        // playlists are stored with library relative paths, whatever the client sent
+        let data = utils::playlist::to_library(&name, &data, None).unwrap_or(data);
+
+        let Some(path) = file_names::path(&library.dir, &name) else {
+            let reason = "the name can't be used on this system".to_string();
+            uploads.insert(name, UploadResult::Rejected { reason });
+            continue;
+        };
+        if let Err(reason) = state.allowlist.check(&name, &data) {
+            uploads.insert(name, UploadResult::Rejected { reason });
+            continue;
+        }
 
-            let music_dir = format!("{}/{}", music_dir, &name);
-            let write_future = tokio::fs::write(music_dir, data_ref);
+        write_tasks.push(async move {
+            let _lock = index.lock_path(&name).await;
+
+            // files the server already has are acknowledged without touching the disk
+            let current = index.get(&name);
+            if current.as_ref().is_some_and(|entry| entry.data == data) {
+                return (name, UploadResult::Identical);
+            }
+            let size = data.len() as u64;
+            if let Err(reason) = grant.quota.check_file(&name, size) {
+                return (name, UploadResult::Rejected { reason });
+            }
+
+            // the file is counted against this token from now on, the one that wrote
+            // the file being replaced gets its bytes back
+            let previous = usage.owner(&library.name, &name);
+            let owner = grant.name.as_ref().map(|user| Owner {
+                token: user.clone(),
+                bytes: size,
+            });
+            if !usage.try_set_owner(&library.name, &name, owner, grant.quota.max_bytes) {
+                let reason = format!(
+                    "over this token's quota, {} of {} bytes are used",
+                    grant.name.as_ref().map_or(0, |user| usage.get(user)),
+                    grant.quota.max_bytes.unwrap_or_default()
+                );
+                return (name, UploadResult::Rejected { reason });
+            }
+            // a replaced file keeps its name on disk, whatever its normalization
+            let path = library.path(&name).unwrap_or(path);
+
+            // the file it replaces goes to the trash, and comes back if the new one can't
+            // be written
+            let kept = if index.contains(&name) {
+                trash
+                    .keep(&library.name, &name, &path, TrashReason::Replaced)
+                    .await
+            } else {
+                Ok(None)
+            };
+            let written = match kept {
+                Ok(kept) => {
+                    let written = tokio::fs::write(&path, &data).await;
+                    if let (Err(_), Some(kept)) = (&written, kept) {
+                        if let Err(err) = trash::move_file(&kept, &path).await {
+                            eprintln!("Failed to put {:?} back: {}", name, err);
+                        }
+                    }
+                    written
+                }
+                Err(err) => Err(err),
+            };
+
+            let result = match written {
+                Ok(()) => {
+                    index.insert(
+                        name.clone(),
+                        Entry::new(&name, &path, data, SystemTime::now()),
+                    );
+                    UploadResult::Written
+                }
+                Err(err) => {
+                    eprintln!("Failed to write {:?}: {}", name, err);
+                    usage.try_set_owner(&library.name, &name, previous, None);
+                    UploadResult::Failed {
+                        error: err.to_string(),
+                    }
+                }
+            };
+            (name, result)
+        });
+    }
 
-            state.file_names.insert(name.clone());
-            state.file_entries.insert(name, data);
+    while let Some((name, result)) = write_tasks.next().await {
+        uploads.insert(name, result);
+    }
 
-            write_tasks.push(write_future);
+    let written = uploads
+        .values()
+        .any(|result| *result == UploadResult::Written);
+    if written {
+        if let Err(err) = usage.save().await {
+            eprintln!("Failed to save the usage of the tokens: {}", err);
         }
+    }
 
-        while (write_tasks.next().await).is_some() {}
-    });
-
-    HttpResponse::Ok().body("synced")
+    let mut response = SyncResponse::new(
+        protocol::PROTOCOL_VERSION,
+        SyncStatus::Synced,
+        state.server_info(),
+    );
+    response.uploads = uploads;
+    Ok(envelope(HttpResponse::Ok(), response.to_bytes()))
 }
 
 #[actix_web::main]
 async fn main() -> io::Result<()> {
     let config = Config::new()?;
     let port = config.port;
+    let trash = Trash::new(&config.trash_dir, config.retention);
+    let snapshots = Snapshots::new(&config.snapshot_dir);
+
+    // `server snapshot ...` works on the files on disk and exits without serving
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if !args.is_empty() {
+        let mut dirs = config.libraries.clone();
+        dirs.insert(DEFAULT_LIBRARY.to_string(), config.music_dir.clone());
+        let result = snapshot::command(
+            &args,
+            &snapshots,
+            &trash,
+            &dirs,
+            config.non_utf8_names,
+            DEFAULT_LIBRARY,
+            port,
+        )
+        .await;
+        if let Err(err) = result {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
     println!("Starting server on 0.0.0.0:{}!", port);
 
-    let token_verifier = TokenVerifier::new(&config.token);
-
-    let (file_names, file_entries) = utils::get_files(&config.music_dir)?;
+    let mut libraries = vec![Library::load(
+        DEFAULT_LIBRARY,
+        &config.music_dir,
+        config.non_utf8_names,
+    )?];
+    for (name, dir) in &config.libraries {
+        libraries.push(Library::load(name, dir, config.non_utf8_names)?);
+    }
+    let mut grants = vec![Grant::all(&config.token)];
+    grants.extend(config.grants.iter().cloned());
+
+    let state = web::Data::new(AppState {
+        libraries: Libraries::new(libraries, grants),
+        uploads: UploadQueue::new(config.max_concurrent_uploads, config.max_upload_memory),
+        usage: Usage::load(USAGE_PATH)?,
+        allowlist: config.allowlist.clone(),
+        trash,
+        snapshots,
+        download_limit: Arc::new(Throttle::new(config.download_limit.clone())),
+        upload_limit: Arc::new(Throttle::new(config.upload_limit.clone())),
+    });
 
-    let state = Arc::new(RwLock::new(AppState {
-        file_names,
-        file_entries,
-        config,
-        token_verifier,
-    }));
+    // versions only age out when something else is moved to the trash, the others are
+    // looked at every hour
+    actix_web::rt::spawn({
+        let state = state.clone();
+        async move {
+            let mut interval = tokio::time::interval(trash::PRUNE_INTERVAL);
+            loop {
+                interval.tick().await;
+                for library in state.libraries.iter() {
+                    if let Err(err) = state.trash.prune(&library.name).await {
+                        eprintln!("Failed to prune the trash of {}: {}", library.name, err);
+                    }
+                }
+            }
+        }
+    });
 
     HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(state.clone()))
-            .app_data(web::PayloadConfig::default().limit(1024 * 1024 * 1024 * 10)) // 10 GB
+            .app_data(state.clone())
             .service(sync_get)
             .service(sync_post)
+            .service(files::file_get)
+            .service(files::file_delete)
+            .service(library::library_get)
+            .service(search::search_get)
+            .service(trash::trash_get)
+            .service(trash::restore_post)
+            .service(snapshot::snapshots_get)
+            .service(snapshot::snapshot_get)
+            .service(snapshot::snapshot_file_get)
+            .service(snapshot::snapshot_restore_post)
+            .service(libraries::libraries_get)
+            .service(
+                web::scope("/libraries/{library}")
+                    .service(sync_get)
+                    .service(sync_post)
+                    .service(files::file_get)
+                    .service(files::file_delete)
+                    .service(library::library_get)
+                    .service(search::search_get)
+                    .service(trash::trash_get)
+                    .service(trash::restore_post)
+                    .service(snapshot::snapshots_get)
+                    .service(snapshot::snapshot_get)
+                    .service(snapshot::snapshot_file_get)
+                    .service(snapshot::snapshot_restore_post),
+            )
     })
     .bind(("0.0.0.0", port))?
     .run()
     .await
 }
+
+/// A server whose libraries live in directories under `root`, for the tests of every
+/// module
+#[cfg(test)]
+impl AppState {
+    fn for_tests(
+        root: &std::path::Path,
+        libraries: &[&str],
+        grants: Vec<Grant>,
+    ) -> web::Data<AppState> {
+        let libraries = libraries.iter().map(|name| {
+            let dir = root.join(name);
+            fs::create_dir_all(&dir).unwrap();
+            Library::load(name, dir.to_str().unwrap(), NonUtf8Names::Escape).unwrap()
+        });
+
+        // with the opt-in checks, the tests upload files that pass them
+        let mut allowlist = Allowlist::default();
+        allowlist.set_types("audio");
+
+        web::Data::new(AppState {
+            libraries: Libraries::new(libraries, grants),
+            uploads: UploadQueue::new(1, queue::DEFAULT_MAX_UPLOAD_MEMORY),
+            usage: Usage::load(root.join("usage.json")).unwrap(),
+            allowlist,
+            trash: Trash::new(root.join("trash"), Retention::default()),
+            snapshots: Snapshots::new(root.join("snapshots")),
+            download_limit: Arc::new(Throttle::new(Schedule::default())),
+            upload_limit: Arc::new(Throttle::new(Schedule::default())),
+        })
+    }
+}
+
+/// The body of an upload of `files`
+#[cfg(test)]
+fn upload_body(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let files: cbf::FileEntries = files
+        .iter()
+        .map(|(name, data)| (name.to_string(), data.to_vec()))
+        .collect();
+    let mut body = Vec::new();
+    cbf::write(&mut body, &files, None).unwrap();
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+    use index::Index;
+    use utils::encryption::TokenVerifier;
+
+    const CLIENTS: usize = 8;
+    const FILES: usize = 20;
+
+    #[actix_web::test]
+    async fn test_concurrent_clients() {
+        let music_dir =
+            std::env::temp_dir().join(format!("music_sync_stress_{}", std::process::id()));
+        fs::create_dir_all(&music_dir).unwrap();
+
+        let token = "stress test token";
+        // the files are made up, only the concurrency matters here
+        let mut allowlist = Allowlist::default();
+        allowlist.set_types("any");
+        let library = Library {
+            name: DEFAULT_LIBRARY.to_string(),
+            dir: music_dir.to_string_lossy().into_owned(),
+            index: Index::new(),
+        };
+        let state = web::Data::new(AppState {
+            libraries: Libraries::new([library], vec![Grant::all(token)]),
+            uploads: UploadQueue::new(CLIENTS, queue::DEFAULT_MAX_UPLOAD_MEMORY),
+            usage: Usage::load(music_dir.join("usage.json")).unwrap(),
+            allowlist,
+            trash: Trash::new(music_dir.join("trash"), Retention::default()),
+            snapshots: Snapshots::new(music_dir.join("snapshots")),
+            download_limit: Arc::new(Throttle::new(Schedule::default())),
+            upload_limit: Arc::new(Throttle::new(Schedule::default())),
+        });
+        let authorization = TokenVerifier::new(token).encrypt(token.as_bytes());
+
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(sync_get)
+                .service(sync_post),
+        )
+        .await;
+
+        // every client uploads its own files and one that all of them share, while as
+        // many others keep asking what they're missing
+        let uploads = (0..CLIENTS).map(|client| {
+            let mut files: cbf::FileEntries = (0..FILES)
+                .map(|i| (format!("{}-{}.mp3", client, i), vec![client as u8; 4096]))
+                .collect();
+            files.insert("shared.mp3".to_string(), b"shared".to_vec());
+
+            let mut body = Vec::new();
+            cbf::write(&mut body, &files, None).unwrap();
+            test::TestRequest::post()
+                .uri("/sync")
+                .insert_header(("Authorization", authorization.clone()))
+                .set_payload(body)
+                .to_request()
+        });
+        let syncs = (0..CLIENTS).map(|_| {
+            test::TestRequest::get()
+                .uri("/sync")
+                .insert_header(("Authorization", authorization.clone()))
+                .set_payload(Manifest::default().to_json())
+                .to_request()
+        });
+
+        let requests = uploads
+            .chain(syncs)
+            .map(|req| test::call_service(&app, req));
+        let responses = futures::future::join_all(requests).await;
+
+        let mut shared_results = Vec::new();
+        for response in responses {
+            assert!(response.status().is_success());
+            let body = test::read_body(response).await;
+            let response = SyncResponse::read(&mut body.as_ref()).unwrap();
+            if let Some(result) = response.uploads.get("shared.mp3") {
+                shared_results.push(result.clone());
+            }
+        }
+
+        // the uploads of the shared file were serialized, only the first one wrote it
+        assert_eq!(shared_results.len(), CLIENTS);
+        let written = shared_results
+            .iter()
+            .filter(|result| **result == UploadResult::Written)
+            .count();
+        assert_eq!(written, 1);
+
+        let index = &state.libraries.iter().next().unwrap().index;
+        assert_eq!(index.len(), CLIENTS * FILES + 1);
+        for client in 0..CLIENTS {
+            let name = format!("{}-{}.mp3", client, FILES - 1);
+            let data = fs::read(music_dir.join(&name)).unwrap();
+            assert_eq!(data, vec![client as u8; 4096]);
+            assert_eq!(index.get(&name).unwrap().data, data);
+        }
+
+        fs::remove_dir_all(music_dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_libraries() {
+        let root =
+            std::env::temp_dir().join(format!("music_sync_libraries_{}", std::process::id()));
+        let state = AppState::for_tests(
+            &root,
+            &[DEFAULT_LIBRARY, "podcasts"],
+            vec![
+                Grant::all("admin"),
+                Grant::parse("guest podcasts:read").unwrap(),
+            ],
+        );
+        let app = test::init_service(
+            App::new().app_data(state).service(
+                web::scope("/libraries/{library}")
+                    .service(sync_get)
+                    .service(sync_post),
+            ),
+        )
+        .await;
+        let authorization = |token: &str| TokenVerifier::new(token).encrypt(token.as_bytes());
+
+        let body = upload_body(&[("episode.mp3", &crate::sniff::mp3(b"episode"))]);
+        let upload = |token: &str, body: Vec<u8>| {
+            test::TestRequest::post()
+                .uri("/libraries/podcasts/sync")
+                .insert_header(("Authorization", authorization(token)))
+                .set_payload(body)
+                .to_request()
+        };
+
+        // uploads land in the directory of the library named by the route
+        let response = test::call_service(&app, upload("admin", body.clone())).await;
+        assert!(response.status().is_success());
+        assert!(root.join("podcasts").join("episode.mp3").exists());
+        assert!(!root.join(DEFAULT_LIBRARY).join("episode.mp3").exists());
+
+        // read only tokens can't upload and aren't asked for anything
+        let response = test::call_service(&app, upload("guest", body)).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::FORBIDDEN);
+
+        let manifest = Manifest {
+            files: BTreeSet::from(["new.mp3".to_string()]),
+            ..Default::default()
+        };
+        let req = test::TestRequest::get()
+            .uri("/libraries/podcasts/sync")
+            .insert_header(("Authorization", authorization("guest")))
+            .set_payload(manifest.to_json())
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let response = SyncResponse::read(&mut body.as_ref()).unwrap();
+        assert!(response.missing.is_empty());
+        assert_eq!(response.status, SyncStatus::Extra);
+
+        // other libraries don't exist for the token
+        let req = test::TestRequest::get()
+            .uri("/libraries/music/sync")
+            .insert_header(("Authorization", authorization("guest")))
+            .set_payload(Manifest::default().to_json())
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::NOT_FOUND);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_upload_names() {
+        let root = std::env::temp_dir().join(format!("music_sync_names_{}", std::process::id()));
+        let state = AppState::for_tests(
+            &root,
+            &[DEFAULT_LIBRARY, "podcasts"],
+            vec![Grant::all("admin")],
+        );
+        let app = test::init_service(App::new().app_data(state.clone()).service(sync_post)).await;
+
+        // names that would point outside of the library are refused, whatever they decode to
+        let body = upload_body(&[
+            ("../podcasts/x.mp3", &crate::sniff::mp3(b"x")),
+            ("%2E%2E", &crate::sniff::mp3(b"x")),
+            ("/tmp/x.mp3", &crate::sniff::mp3(b"x")),
+            ("song.mp3", &crate::sniff::mp3(b"x")),
+        ]);
+        let req = test::TestRequest::post()
+            .uri("/sync")
+            .insert_header((
+                "Authorization",
+                TokenVerifier::new("admin").encrypt(b"admin"),
+            ))
+            .set_payload(body)
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let response = SyncResponse::read(&mut body.as_ref()).unwrap();
+
+        for name in ["../podcasts/x.mp3", "%2E%2E", "/tmp/x.mp3"] {
+            assert!(
+                matches!(response.uploads[name], UploadResult::Rejected { .. }),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(response.uploads["song.mp3"], UploadResult::Written);
+        assert!(!root.join("podcasts").join("x.mp3").exists());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
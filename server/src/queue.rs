@@ -0,0 +1,110 @@
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::error::ServerError;
+
+pub const DEFAULT_MAX_CONCURRENT_UPLOADS: usize = 4;
+pub const DEFAULT_MAX_UPLOAD_MEMORY: u64 = 1024 * 1024 * 1024;
+/// Below this the largest upload couldn't even hold a few small files, since an upload
+/// reserves twice its length
+pub const MIN_MAX_UPLOAD_MEMORY: u64 = 2 * UNIT;
+
+/// How long clients are asked to wait when the queue is full
+pub const RETRY_AFTER_SECS: u64 = 5;
+
+/// Memory is reserved in KiB so that the largest reservation fits in a `u32` permit count
+const UNIT: u64 = 1024;
+
+/// Bounds the number of uploads being processed and the bytes they hold in memory.
+/// Uploads are turned away with a 503 instead of waiting, so a busy server never
+/// piles up request bodies. The body and the files parsed from it are both held for a
+/// moment, so an upload reserves twice its length.
+pub struct UploadQueue {
+    uploads: Arc<Semaphore>,
+    memory: Arc<Semaphore>,
+    max_upload_bytes: u64,
+}
+
+/// Keeps an upload's place in the queue until it's dropped
+pub struct UploadPermit {
+    _upload: OwnedSemaphorePermit,
+    _memory: OwnedSemaphorePermit,
+}
+
+impl UploadQueue {
+    pub fn new(max_concurrent_uploads: usize, max_upload_memory: u64) -> Self {
+        Self {
+            uploads: Arc::new(Semaphore::new(max_concurrent_uploads)),
+            memory: Arc::new(Semaphore::new(units(max_upload_memory) as usize)),
+            max_upload_bytes: max_upload_memory / 2,
+        }
+    }
+
+    /// The largest upload that can ever be accepted, the memory limit is shared by all
+    /// the uploads in progress
+    pub fn max_upload_bytes(&self) -> u64 {
+        self.max_upload_bytes
+    }
+
+    pub fn try_reserve(&self, length: u64) -> Result<UploadPermit, ServerError> {
+        if length > self.max_upload_bytes {
+            return Err(ServerError::UploadTooLarge(self.max_upload_bytes));
+        }
+
+        let busy = || ServerError::Busy(RETRY_AFTER_SECS);
+        let upload = self
+            .uploads
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| busy())?;
+        let memory = self
+            .memory
+            .clone()
+            .try_acquire_many_owned(units(2 * length))
+            .map_err(|_| busy())?;
+
+        Ok(UploadPermit {
+            _upload: upload,
+            _memory: memory,
+        })
+    }
+}
+
+fn units(bytes: u64) -> u32 {
+    bytes.div_ceil(UNIT).min(u32::MAX as u64) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_try_reserve() {
+        let queue = UploadQueue::new(2, 20 * UNIT);
+
+        assert!(matches!(
+            queue.try_reserve(11 * UNIT),
+            Err(ServerError::UploadTooLarge(_))
+        ));
+
+        // 12 KiB of the 20 are reserved
+        let first = queue.try_reserve(6 * UNIT).unwrap();
+        // not enough memory left
+        assert!(matches!(
+            queue.try_reserve(5 * UNIT),
+            Err(ServerError::Busy(_))
+        ));
+
+        let second = queue.try_reserve(4 * UNIT).unwrap();
+        // no upload slot left
+        assert!(matches!(queue.try_reserve(0), Err(ServerError::Busy(_))));
+
+        drop((first, second));
+        assert!(queue.try_reserve(10 * UNIT).is_ok());
+
+        // the smallest memory limit still takes an upload of half of it
+        let queue = UploadQueue::new(1, MIN_MAX_UPLOAD_MEMORY);
+        assert!(queue.try_reserve(MIN_MAX_UPLOAD_MEMORY / 2).is_ok());
+    }
+}
@@ -0,0 +1,355 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::files::extension;
+
+/// What a token may upload, every file that breaks a limit is rejected on its own
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Quota {
+    /// The bytes the token may write in total, across every library
+    pub max_bytes: Option<u64>,
+    pub max_file_size: Option<u64>,
+    /// Lowercase extensions without the dot, `None` allows every file
+    pub extensions: Option<HashSet<String>>,
+}
+
+impl Quota {
+    /// Sets one of the `key=value` options of a `token` setting
+    pub fn set(&mut self, key: &str, value: &str) -> bool {
+        match key {
+            "max_bytes" => utils::parse_size(value)
+                .map(|value| self.max_bytes = Some(value))
+                .is_some(),
+            "max_file_size" => utils::parse_size(value)
+                .map(|value| self.max_file_size = Some(value))
+                .is_some(),
+            "extensions" => {
+                let extensions = value
+                    .split(',')
+                    .map(|extension| extension.trim().trim_start_matches('.').to_lowercase())
+                    .filter(|extension| !extension.is_empty())
+                    .collect::<HashSet<_>>();
+                self.extensions = Some(extensions);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Why the file can't be uploaded whatever the token uploaded before
+    pub fn check_file(&self, name: &str, size: u64) -> Result<(), String> {
+        if let Some(extensions) = &self.extensions {
+            let extension = extension(name);
+            if !extensions.contains(&extension) {
+                return Err(match extension.as_str() {
+                    "" => "files without an extension aren't allowed for this token".to_string(),
+                    _ => format!(".{} files aren't allowed for this token", extension),
+                });
+            }
+        }
+
+        match self.max_file_size {
+            Some(max_file_size) if size > max_file_size => Err(format!(
+                "larger than the {} bytes this token may upload per file",
+                max_file_size
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The token that wrote a file, and the bytes the file counts for
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Owner {
+    pub token: String,
+    pub bytes: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Counts {
+    /// The bytes of every token with a name
+    tokens: BTreeMap<String, u64>,
+    /// The owner of every file written by one of them, by library and name
+    files: BTreeMap<String, BTreeMap<String, Owner>>,
+}
+
+/// The bytes every token with a name has written, kept in `usage.json` so that quotas
+/// hold across restarts. Each file is counted against the token that wrote it, whoever
+/// replaces or deletes it later.
+pub struct Usage {
+    path: PathBuf,
+    counts: Mutex<Counts>,
+    /// Serializes the saves, they all go through the same temporary file
+    save_lock: tokio::sync::Mutex<()>,
+}
+
+impl Usage {
+    pub fn load(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let counts = match std::fs::read_to_string(&path) {
+            Ok(buffer) => serde_json::from_str(&buffer)
+                // before files had owners only the bytes of the tokens were kept
+                .or_else(|_| {
+                    serde_json::from_str(&buffer).map(|tokens| Counts {
+                        tokens,
+                        files: BTreeMap::new(),
+                    })
+                })
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Counts::default(),
+            Err(err) => return Err(err),
+        };
+
+        Ok(Self {
+            path,
+            counts: Mutex::new(counts),
+            save_lock: tokio::sync::Mutex::default(),
+        })
+    }
+
+    pub fn get(&self, token: &str) -> u64 {
+        let counts = self.counts.lock().unwrap();
+        counts.tokens.get(token).copied().unwrap_or(0)
+    }
+
+    pub fn owner(&self, library: &str, name: &str) -> Option<Owner> {
+        let counts = self.counts.lock().unwrap();
+        counts.files.get(library)?.get(name).cloned()
+    }
+
+    /// Counts the file against `owner` instead of the token that owned it until now,
+    /// which gets its bytes back. `None` is a file no token is charged for, deleted or
+    /// written by a token without a name. Only growing needs room in `max_bytes`, a
+    /// token over its quota can still replace its files with smaller ones. Writes that
+    /// are checked at the same time can't both take the last bytes of a quota.
+    pub fn try_set_owner(
+        &self,
+        library: &str,
+        name: &str,
+        owner: Option<Owner>,
+        max_bytes: Option<u64>,
+    ) -> bool {
+        let mut counts = self.counts.lock().unwrap();
+        let Counts { tokens, files } = &mut *counts;
+        let previous = files.get(library).and_then(|files| files.get(name));
+
+        if let Some(owner) = &owner {
+            let freed = previous
+                .filter(|previous| previous.token == owner.token)
+                .map_or(0, |previous| previous.bytes);
+            let used = tokens.get(&owner.token).copied().unwrap_or(0);
+            let new_used = used.saturating_sub(freed) + owner.bytes;
+            if owner.bytes > freed && max_bytes.is_some_and(|max_bytes| new_used > max_bytes) {
+                return false;
+            }
+        }
+
+        let library_files = files.entry(library.to_string()).or_default();
+        if let Some(previous) = library_files.remove(name) {
+            if let Some(used) = tokens.get_mut(&previous.token) {
+                *used = used.saturating_sub(previous.bytes);
+            }
+        }
+        match owner {
+            Some(owner) => {
+                *tokens.entry(owner.token.clone()).or_default() += owner.bytes;
+                library_files.insert(name.to_string(), owner);
+            }
+            None if library_files.is_empty() => {
+                files.remove(library);
+            }
+            None => {}
+        }
+        true
+    }
+
+    pub async fn save(&self) -> io::Result<()> {
+        let _lock = self.save_lock.lock().await;
+        let buffer = serde_json::to_string_pretty(&*self.counts.lock().unwrap())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        let temp_path = self.path.with_extension("json.tmp");
+        tokio::fs::write(&temp_path, buffer).await?;
+        tokio::fs::rename(temp_path, &self.path).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_file() {
+        let mut quota = Quota::default();
+        assert!(quota.set("max_file_size", "1K"));
+        assert!(quota.set("extensions", "mp3, .FLAC"));
+        assert!(!quota.set("max_file_size", "huge"));
+
+        assert_eq!(quota.check_file("song.flac", 1024), Ok(()));
+        assert_eq!(
+            quota.check_file("song.exe", 10),
+            Err(".exe files aren't allowed for this token".to_string())
+        );
+        assert_eq!(
+            quota.check_file("song.mp3", 1025),
+            Err("larger than the 1024 bytes this token may upload per file".to_string())
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_usage() {
+        let path =
+            std::env::temp_dir().join(format!("music_sync_usage_{}.json", std::process::id()));
+        let usage = Usage::load(&path).unwrap();
+        let owner = |token: &str, bytes| {
+            Some(Owner {
+                token: token.to_string(),
+                bytes,
+            })
+        };
+
+        assert!(usage.try_set_owner("music", "a", owner("phone", 60), Some(100)));
+        assert!(!usage.try_set_owner("music", "b", owner("phone", 60), Some(100)));
+        assert!(usage.try_set_owner("music", "b", owner("phone", 40), Some(100)));
+        assert!(usage.try_set_owner("music", "c", owner("laptop", 1000), None));
+
+        // replacing its own file only counts the difference, and shrinking always fits
+        assert!(!usage.try_set_owner("music", "b", owner("phone", 50), Some(100)));
+        assert!(usage.try_set_owner("music", "a", owner("phone", 50), Some(100)));
+        assert!(usage.try_set_owner("music", "c", owner("laptop", 500), Some(100)));
+        assert_eq!(usage.get("phone"), 90);
+
+        // the bytes of a file go back to the token that wrote it, whoever replaces it
+        assert!(usage.try_set_owner("music", "b", owner("laptop", 40), None));
+        assert!(usage.try_set_owner("music", "c", None, None));
+        assert_eq!((usage.get("phone"), usage.get("laptop")), (50, 40));
+        assert_eq!(usage.owner("music", "b"), owner("laptop", 40));
+        assert_eq!(usage.owner("music", "c"), None);
+
+        usage.save().await.unwrap();
+        let usage = Usage::load(&path).unwrap();
+        assert_eq!((usage.get("phone"), usage.get("laptop")), (50, 40));
+        assert_eq!(usage.owner("music", "a"), owner("phone", 50));
+
+        // the usage saved before files had owners is still read
+        std::fs::write(&path, r#"{"phone": 834}"#).unwrap();
+        assert_eq!(Usage::load(&path).unwrap().get("phone"), 834);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_upload_quota() {
+        use crate::{files::file_delete, libraries::Grant, sniff::mp3, sync_post, upload_body};
+        use crate::{AppState, DEFAULT_LIBRARY};
+        use actix_web::{test, App};
+        use utils::{
+            encryption::TokenVerifier,
+            protocol::{SyncResponse, UploadResult},
+        };
+
+        let root = std::env::temp_dir().join(format!("music_sync_quota_{}", std::process::id()));
+        let state = AppState::for_tests(
+            &root,
+            &[DEFAULT_LIBRARY],
+            vec![
+                Grant::all("admin"),
+                Grant::parse("phone music name=phone max_bytes=1000 extensions=mp3").unwrap(),
+                Grant::parse("tablet music name=tablet max_bytes=1000").unwrap(),
+            ],
+        );
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(sync_post)
+                .service(file_delete),
+        )
+        .await;
+        let upload_as = |token: &str, files: &[(&str, &[u8])]| {
+            test::TestRequest::post()
+                .uri("/sync")
+                .insert_header((
+                    "Authorization",
+                    TokenVerifier::new(token).encrypt(token.as_bytes()),
+                ))
+                .set_payload(upload_body(files))
+                .to_request()
+        };
+
+        // files over a quota are rejected one by one and the rest is written
+        let body = test::call_and_read_body(
+            &app,
+            upload_as(
+                "phone",
+                &[
+                    ("a.mp3", &mp3(b"a")),
+                    ("b.mp3", &mp3(b"b")),
+                    ("c.flac", b"fLaC"),
+                ],
+            ),
+        )
+        .await;
+        let uploads = SyncResponse::read(&mut body.as_ref()).unwrap().uploads;
+        let written = uploads
+            .values()
+            .filter(|result| **result == UploadResult::Written)
+            .count();
+        assert_eq!(written, 1);
+        assert_eq!(
+            uploads["c.flac"],
+            UploadResult::Rejected {
+                reason: ".flac files aren't allowed for this token".to_string()
+            }
+        );
+        assert_eq!(state.usage.get("phone"), 834);
+        assert!(root.join("usage.json").exists());
+        let (name, data) = if uploads["a.mp3"] == UploadResult::Written {
+            ("a.mp3", mp3(b"a"))
+        } else {
+            ("b.mp3", mp3(b"b"))
+        };
+
+        // sending it again costs nothing, and replacing it only counts the difference
+        for (data, result) in [
+            (data, UploadResult::Identical),
+            (mp3(b"new"), UploadResult::Written),
+        ] {
+            let body = test::call_and_read_body(&app, upload_as("phone", &[(name, &data)])).await;
+            let uploads = SyncResponse::read(&mut body.as_ref()).unwrap().uploads;
+            assert_eq!(uploads[name], result);
+            assert_eq!(state.usage.get("phone"), 834);
+        }
+
+        // a file is counted against the token that wrote it last, another one replacing
+        // it takes it over
+        for (token, other) in [("tablet", "phone"), ("phone", "tablet")] {
+            let data = mp3(token.as_bytes());
+            let body = test::call_and_read_body(&app, upload_as(token, &[(name, &data)])).await;
+            let uploads = SyncResponse::read(&mut body.as_ref()).unwrap().uploads;
+            assert_eq!(uploads[name], UploadResult::Written);
+            assert_eq!((state.usage.get(token), state.usage.get(other)), (834, 0));
+        }
+
+        // deleting it gives the bytes back to that token, not to the one deleting it
+        let data = mp3(b"t");
+        test::call_service(&app, upload_as("tablet", &[("t.mp3", &data)])).await;
+        let req = test::TestRequest::delete()
+            .uri(&format!("/files/{}", name))
+            .insert_header((
+                "Authorization",
+                TokenVerifier::new("tablet").encrypt(b"tablet"),
+            ))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        assert_eq!(
+            (state.usage.get("phone"), state.usage.get("tablet")),
+            (0, 834)
+        );
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,344 @@
+use std::cmp::Ordering;
+
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use utils::tags::TrackInfo;
+
+use crate::library::{LibraryTrack, DEFAULT_LIMIT, MAX_LIMIT};
+use crate::{error::ServerError, libraries::Access, AppState};
+
+/// Free terms are matched against these fields, the weight is how much a match counts
+const FREE_TEXT_FIELDS: [(&str, f32); 4] = [
+    ("title", 3.0),
+    ("artist", 2.0),
+    ("album", 1.5),
+    ("name", 1.0),
+];
+
+#[derive(Debug, PartialEq)]
+enum Comparison {
+    Equal,
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+}
+
+#[derive(Debug, PartialEq)]
+enum Term {
+    /// Matched against the title, artist, album and file name
+    Free(String),
+    Text {
+        field: String,
+        value: String,
+    },
+    Number {
+        field: String,
+        comparison: Comparison,
+        value: u64,
+    },
+}
+
+/// Splits the query on whitespace, keeping double quoted values together,
+/// e.g. `artist:"pink floyd" year:>1970 money`
+fn parse_query(query: &str) -> Vec<Term> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+
+    for c in query.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    words.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        words.push(current);
+    }
+
+    words.into_iter().map(|word| parse_term(&word)).collect()
+}
+
+fn parse_term(word: &str) -> Term {
+    let Some((field, value)) = word.split_once(':') else {
+        return Term::Free(word.to_lowercase());
+    };
+    let field = field.to_lowercase();
+
+    match field.as_str() {
+        "year" | "track" | "duration" | "bitrate" => {
+            let (comparison, number) = if let Some(number) = value.strip_prefix(">=") {
+                (Comparison::GreaterOrEqual, number)
+            } else if let Some(number) = value.strip_prefix("<=") {
+                (Comparison::LessOrEqual, number)
+            } else if let Some(number) = value.strip_prefix('>') {
+                (Comparison::Greater, number)
+            } else if let Some(number) = value.strip_prefix('<') {
+                (Comparison::Less, number)
+            } else {
+                (Comparison::Equal, value.trim_start_matches('='))
+            };
+
+            match number.parse() {
+                Ok(value) => Term::Number {
+                    field,
+                    comparison,
+                    value,
+                },
+                Err(_) => Term::Free(word.to_lowercase()),
+            }
+        }
+        "title" | "artist" | "album" | "genre" | "name" => Term::Text {
+            field,
+            value: value.to_lowercase(),
+        },
+        _ => Term::Free(word.to_lowercase()),
+    }
+}
+
+fn number_field(info: &TrackInfo, field: &str) -> Option<u64> {
+    match field {
+        "year" => info.year.map(u64::from),
+        "track" => info.track_number.map(u64::from),
+        // durations are written in seconds in queries
+        "duration" => info.duration_ms.map(|duration| duration / 1000),
+        "bitrate" => info.bitrate.map(u64::from),
+        _ => None,
+    }
+}
+
+fn text_field(name: &str, info: &TrackInfo, field: &str) -> Option<String> {
+    match field {
+        "name" => Some(name.to_lowercase()),
+        field => info.field(field).map(|value| value.to_lowercase()),
+    }
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+
+    for (i, a) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    previous[b.len()]
+}
+
+/// How well `needle` matches `haystack`, 0 means it doesn't.
+/// Whole words score best, then prefixes, substrings and finally typos.
+fn match_score(haystack: &str, needle: &str) -> f32 {
+    if needle.is_empty() {
+        return 0.0;
+    }
+    if haystack == needle {
+        return 1.5;
+    }
+
+    let words = haystack.split(|c: char| !c.is_alphanumeric());
+    let mut best: f32 = if haystack.contains(needle) { 0.6 } else { 0.0 };
+
+    let max_typos = match needle.chars().count() {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    };
+
+    for word in words.filter(|word| !word.is_empty()) {
+        let score = if word == needle {
+            1.0
+        } else if word.starts_with(needle) {
+            0.8
+        } else if max_typos > 0 && levenshtein(word, needle) <= max_typos {
+            0.4
+        } else {
+            0.0
+        };
+        best = best.max(score);
+    }
+
+    best
+}
+
+/// Every term has to match, the sum of the term scores is used for ranking
+fn score(name: &str, info: &TrackInfo, terms: &[Term]) -> Option<f32> {
+    let mut total = 0.0;
+
+    for term in terms {
+        let term_score = match term {
+            Term::Free(value) => FREE_TEXT_FIELDS
+                .iter()
+                .filter_map(|(field, weight)| {
+                    let haystack = text_field(name, info, field)?;
+                    Some(match_score(&haystack, value) * weight)
+                })
+                .fold(0.0, f32::max),
+            Term::Text { field, value } => text_field(name, info, field)
+                .map_or(0.0, |haystack| match_score(&haystack, value) * 2.0),
+            Term::Number {
+                field,
+                comparison,
+                value,
+            } => {
+                let matches = number_field(info, field).is_some_and(|number| match comparison {
+                    Comparison::Equal => number == *value,
+                    Comparison::Greater => number > *value,
+                    Comparison::GreaterOrEqual => number >= *value,
+                    Comparison::Less => number < *value,
+                    Comparison::LessOrEqual => number <= *value,
+                });
+                if matches {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+        };
+
+        if term_score <= 0.0 {
+            return None;
+        }
+        total += term_score;
+    }
+
+    Some(total)
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct SearchResult<'a> {
+    score: f32,
+    #[serde(flatten)]
+    track: LibraryTrack<'a>,
+}
+
+#[derive(Serialize)]
+struct SearchPage<'a> {
+    total: usize,
+    offset: usize,
+    limit: usize,
+    tracks: Vec<SearchResult<'a>>,
+}
+
+#[get("/search")]
+async fn search_get(
+    state: web::Data<AppState>,
+    query: web::Query<SearchQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ServerError> {
+    let library = state.libraries.authorize(&req, Access::Read)?.library;
+
+    let terms = parse_query(&query.q);
+    if terms.is_empty() {
+        return Err(ServerError::InvalidQuery("empty query"));
+    }
+
+    let snapshot = library.index.snapshot();
+    let mut results = snapshot
+        .iter()
+        .filter_map(|(name, entry)| {
+            let track = LibraryTrack::new(name, entry)?;
+            Some(SearchResult {
+                score: score(name, track.info, &terms)?,
+                track,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    results.sort_by(|a, b| {
+        b.score
+            .partial_cmp(&a.score)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| a.track.name.cmp(b.track.name))
+    });
+
+    let total = results.len();
+    let offset = query.offset.unwrap_or(0);
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+
+    let tracks = results.into_iter().skip(offset).take(limit).collect();
+
+    Ok(HttpResponse::Ok().json(SearchPage {
+        total,
+        offset,
+        limit,
+        tracks,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(title: &str, artist: &str, year: u32) -> TrackInfo {
+        TrackInfo {
+            title: Some(title.to_string()),
+            artist: Some(artist.to_string()),
+            year: Some(year),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let terms = parse_query(r#"artist:"Pink Floyd" year:>1970 Money"#);
+
+        assert_eq!(
+            terms,
+            vec![
+                Term::Text {
+                    field: "artist".to_string(),
+                    value: "pink floyd".to_string()
+                },
+                Term::Number {
+                    field: "year".to_string(),
+                    comparison: Comparison::Greater,
+                    value: 1970
+                },
+                Term::Free("money".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fuzzy_match() {
+        assert!(match_score("comfortably numb", "comfortabley") > 0.0);
+        assert!(match_score("time", "tme") == 0.0);
+        assert!(match_score("money", "money") > match_score("money for nothing", "money"));
+    }
+
+    #[test]
+    fn test_score() {
+        let info = track("Money", "Pink Floyd", 1973);
+
+        assert!(score("money.flac", &info, &parse_query("year:>1970 money")).is_some());
+        assert!(score("money.flac", &info, &parse_query("year:<1970 money")).is_none());
+        assert!(score("money.flac", &info, &parse_query("artist:floyd")).is_some());
+        assert!(score("money.flac", &info, &parse_query("album:floyd")).is_none());
+
+        let title_match = score(
+            "a.flac",
+            &track("Pink", "Someone", 2000),
+            &parse_query("pink"),
+        );
+        let artist_match = score("b.flac", &info, &parse_query("pink"));
+        assert!(title_match > artist_match);
+    }
+}
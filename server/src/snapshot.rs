@@ -0,0 +1,631 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use utils::{
+    file_names::NonUtf8Names,
+    protocol::{self, Snapshot, SnapshotFile, SnapshotInfo, TrashReason},
+};
+
+use crate::{
+    error::ServerError,
+    files::mime_type,
+    index::Entry,
+    libraries::{Access, Library},
+    quota::{Owner, Usage},
+    trash::Trash,
+    AppState, USAGE_PATH,
+};
+
+pub const DEFAULT_SNAPSHOT_DIR: &str = "snapshots";
+
+/// What restoring a snapshot changed in a library
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Restored {
+    pub written: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+}
+
+/// The snapshots of every library. A snapshot is only a list of names and content
+/// hashes in `snapshots/{library}/{id}.json`, the content is stored once per hash in
+/// `snapshots/objects/`, whatever the snapshot or library it belongs to. Taking one
+/// more snapshot of a library that barely changed costs little more than its list.
+pub struct Snapshots {
+    dir: PathBuf,
+}
+
+impl Snapshots {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn manifest_path(&self, library: &str, id: u64) -> PathBuf {
+        self.dir.join(library).join(format!("{}.json", id))
+    }
+
+    fn object_path(&self, hash: &str) -> PathBuf {
+        self.dir.join("objects").join(&hash[..2]).join(hash)
+    }
+
+    /// Records every file of the library in `dir` as it is on disk
+    pub async fn create(
+        &self,
+        library: &str,
+        dir: &str,
+        non_utf8_names: NonUtf8Names,
+    ) -> io::Result<Snapshot> {
+        let dir = dir.to_string();
+        let (_, entries) =
+            tokio::task::spawn_blocking(move || utils::get_files(&dir, non_utf8_names))
+                .await
+                .map_err(io::Error::other)??;
+
+        let mut files = BTreeMap::new();
+        for (name, data) in entries {
+            let hash = protocol::content_hash(&data);
+            let object = self.object_path(&hash);
+            if !tokio::fs::try_exists(&object).await? {
+                tokio::fs::create_dir_all(object.parent().unwrap()).await?;
+                // objects are never partial, a snapshot can always be restored from them
+                let temp_path = object.with_extension("tmp");
+                tokio::fs::write(&temp_path, &data).await?;
+                tokio::fs::rename(temp_path, &object).await?;
+            }
+            let size = data.len() as u64;
+            files.insert(name, SnapshotFile { hash, size });
+        }
+
+        let mut id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        while tokio::fs::try_exists(self.manifest_path(library, id)).await? {
+            id += 1;
+        }
+        let snapshot = Snapshot { id, files };
+
+        let path = self.manifest_path(library, id);
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        let buffer = serde_json::to_vec_pretty(&snapshot).map_err(io::Error::other)?;
+        let temp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&temp_path, buffer).await?;
+        tokio::fs::rename(temp_path, path).await?;
+
+        Ok(snapshot)
+    }
+
+    /// The snapshots of the library, oldest first
+    pub async fn list(&self, library: &str) -> io::Result<Vec<SnapshotInfo>> {
+        let mut entries = match tokio::fs::read_dir(self.dir.join(library)).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut snapshots = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let file_name = entry.file_name();
+            let id = file_name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".json")?.parse().ok());
+            if let Some(snapshot) = match id {
+                Some(id) => self.load(library, id).await?,
+                None => None,
+            } {
+                snapshots.push(SnapshotInfo::from(&snapshot));
+            }
+        }
+        snapshots.sort_by_key(|snapshot| snapshot.id);
+        Ok(snapshots)
+    }
+
+    /// The snapshot, checked so that its hashes can be used as object paths
+    pub async fn load(&self, library: &str, id: u64) -> io::Result<Option<Snapshot>> {
+        let snapshot: Snapshot = match tokio::fs::read(self.manifest_path(library, id)).await {
+            Ok(buffer) => serde_json::from_slice(&buffer)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let is_hash = |hash: &str| {
+            hash.len() == 64
+                && hash
+                    .bytes()
+                    .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+        };
+        if let Some((name, _)) = snapshot.files.iter().find(|(_, file)| !is_hash(&file.hash)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("snapshot {} has an invalid hash for {:?}", id, name),
+            ));
+        }
+        Ok(Some(snapshot))
+    }
+
+    /// The content of a file of a snapshot
+    pub async fn read(&self, file: &SnapshotFile) -> io::Result<Vec<u8>> {
+        tokio::fs::read(self.object_path(&file.hash)).await
+    }
+
+    /// Brings the library back to the snapshot, through its index so that the files are
+    /// served as restored right away. Files that aren't in it or differ are moved to the
+    /// trash first, so restoring can be undone. The files it writes are counted against
+    /// `token` like uploads, the ones it moves away give their bytes back.
+    pub async fn restore(
+        &self,
+        snapshot: &Snapshot,
+        library: &Library,
+        trash: &Trash,
+        usage: &Usage,
+        token: Option<&str>,
+    ) -> io::Result<Restored> {
+        let index = &library.index;
+        let mut restored = Restored::default();
+
+        for (name, _) in index.snapshot() {
+            if snapshot.files.contains_key(&name) {
+                continue;
+            }
+            let _lock = index.lock_path(&name).await;
+            // it may have been deleted meanwhile
+            let Some(path) = index.get(&name).and(library.path(&name)) else {
+                continue;
+            };
+            trash
+                .keep(&library.name, &name, &path, TrashReason::Deleted)
+                .await?;
+            index.remove(&name);
+            usage.try_set_owner(&library.name, &name, None, None);
+            restored.removed += 1;
+        }
+
+        for (name, file) in &snapshot.files {
+            let _lock = index.lock_path(name).await;
+            let path = library.path(name).ok_or_else(|| {
+                io::Error::other(format!("{:?} can't be used on this system", name))
+            })?;
+            match index.get(name) {
+                Some(entry) if protocol::content_hash(&entry.data) == file.hash => {
+                    restored.unchanged += 1;
+                    continue;
+                }
+                Some(_) => {
+                    trash
+                        .keep(&library.name, name, &path, TrashReason::Replaced)
+                        .await?;
+                }
+                None => {}
+            }
+            let data = self.read(file).await?;
+            tokio::fs::write(&path, &data).await?;
+            let owner = token.map(|token| Owner {
+                token: token.to_string(),
+                bytes: data.len() as u64,
+            });
+            usage.try_set_owner(&library.name, name, owner, None);
+            index.insert(
+                name.clone(),
+                Entry::new(name, &path, data, SystemTime::now()),
+            );
+            restored.written += 1;
+        }
+
+        Ok(restored)
+    }
+}
+
+/// `server snapshot create|list|restore [ID] [LIBRARY]`, run instead of the server.
+/// `libraries` holds the directory of every library by name. Restoring refuses to run
+/// while a server listens on `port`, it couldn't tell that server the files changed.
+pub async fn command(
+    args: &[String],
+    snapshots: &Snapshots,
+    trash: &Trash,
+    libraries: &BTreeMap<String, String>,
+    non_utf8_names: NonUtf8Names,
+    default_library: &str,
+    port: u16,
+) -> io::Result<()> {
+    let usage = || {
+        io::Error::other(
+            "Usage: server snapshot create [LIBRARY]\n       server snapshot list [LIBRARY]\n       server snapshot restore ID [LIBRARY]",
+        )
+    };
+    let library_dir = |name: Option<&String>| {
+        let name = name.map_or(default_library, String::as_str);
+        libraries
+            .get_key_value(name)
+            .ok_or_else(|| io::Error::other(format!("No such library: {}", name)))
+    };
+
+    match args {
+        [snapshot, command, rest @ ..] if snapshot == "snapshot" && rest.len() <= 2 => {
+            match (command.as_str(), rest) {
+                ("create", [] | [_]) => {
+                    let (library, dir) = library_dir(rest.first())?;
+                    let snapshot = snapshots.create(library, dir, non_utf8_names).await?;
+                    let info = SnapshotInfo::from(&snapshot);
+                    println!(
+                        "Created snapshot {} of {} with {} files ({} bytes)",
+                        info.id, library, info.files, info.bytes
+                    );
+                }
+                ("list", [] | [_]) => {
+                    let (library, _) = library_dir(rest.first())?;
+                    for info in snapshots.list(library).await? {
+                        println!("{}  {} files  {} bytes", info.id, info.files, info.bytes);
+                    }
+                }
+                ("restore", [id] | [id, _]) => {
+                    let (library, dir) = library_dir(rest.get(1))?;
+                    let id = id.parse().map_err(|_| usage())?;
+                    let snapshot = snapshots
+                        .load(library, id)
+                        .await?
+                        .ok_or_else(|| io::Error::other(format!("No such snapshot: {}", id)))?;
+                    if let Err(err) = std::net::TcpListener::bind(("0.0.0.0", port)) {
+                        if err.kind() == io::ErrorKind::AddrInUse {
+                            return Err(io::Error::other(format!(
+                                "The server is running, restore the snapshot with POST /libraries/{}/snapshots/{}/restore or stop it first",
+                                library, id
+                            )));
+                        }
+                    }
+                    let library = Library::load(library, dir, non_utf8_names)?;
+                    let usage = Usage::load(USAGE_PATH)?;
+                    let restored = snapshots
+                        .restore(&snapshot, &library, trash, &usage, None)
+                        .await;
+                    usage.save().await?;
+                    let restored = restored?;
+                    println!(
+                        "Restored {} to snapshot {}: {} files written, {} moved to the trash, {} unchanged",
+                        library.name, id, restored.written, restored.removed, restored.unchanged
+                    );
+                }
+                _ => return Err(usage()),
+            }
+            Ok(())
+        }
+        _ => Err(usage()),
+    }
+}
+
+#[derive(Deserialize)]
+struct SnapshotPath {
+    id: u64,
+}
+
+#[derive(Deserialize)]
+struct SnapshotFilePath {
+    id: u64,
+    path: String,
+}
+
+/// The snapshots of the library, oldest first
+#[get("/snapshots")]
+async fn snapshots_get(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ServerError> {
+    let library = state.libraries.authorize(&req, Access::Read)?.library;
+
+    let snapshots = state
+        .snapshots
+        .list(&library.name)
+        .await
+        .map_err(ServerError::Io)?;
+    Ok(HttpResponse::Ok().json(snapshots))
+}
+
+/// Every file of a snapshot with its content hash
+#[get("/snapshots/{id}")]
+async fn snapshot_get(
+    state: web::Data<AppState>,
+    path: web::Path<SnapshotPath>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ServerError> {
+    let library = state.libraries.authorize(&req, Access::Read)?.library;
+
+    let snapshot = state
+        .snapshots
+        .load(&library.name, path.id)
+        .await
+        .map_err(ServerError::Io)?
+        .ok_or(ServerError::NotFound)?;
+    Ok(HttpResponse::Ok().json(snapshot))
+}
+
+/// Brings the library back to a snapshot. The token is charged for the files it writes,
+/// like for an upload, and the ones that wrote the files it replaces or removes get their
+/// bytes back.
+#[post("/snapshots/{id}/restore")]
+async fn snapshot_restore_post(
+    state: web::Data<AppState>,
+    path: web::Path<SnapshotPath>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ServerError> {
+    let authorized = state.libraries.authorize(&req, Access::Write)?;
+    let (library, grant) = (authorized.library, authorized.grant);
+
+    let snapshot = state
+        .snapshots
+        .load(&library.name, path.id)
+        .await
+        .map_err(ServerError::Io)?
+        .ok_or(ServerError::NotFound)?;
+
+    // the check is made up front, the restore itself doesn't stop halfway for the quota
+    if let (Some(user), Some(max_bytes)) = (&grant.name, grant.quota.max_bytes) {
+        let used = state.usage.get(user);
+        let (freed, added) = restore_bytes(&snapshot, library, &state.usage, user);
+        if added > freed && used.saturating_sub(freed) + added > max_bytes {
+            return Err(ServerError::OverQuota(used, max_bytes));
+        }
+    }
+
+    let restored = state
+        .snapshots
+        .restore(
+            &snapshot,
+            library,
+            &state.trash,
+            &state.usage,
+            grant.name.as_deref(),
+        )
+        .await;
+    if let Err(err) = state.usage.save().await {
+        eprintln!("Failed to save the usage of the tokens: {}", err);
+    }
+
+    Ok(HttpResponse::Ok().json(restored.map_err(ServerError::Io)?))
+}
+
+/// The bytes of the files of `token` that restoring the snapshot replaces or removes, and
+/// the bytes of the files it writes
+fn restore_bytes(snapshot: &Snapshot, library: &Library, usage: &Usage, token: &str) -> (u64, u64) {
+    let owned = |name: &str| {
+        usage
+            .owner(&library.name, name)
+            .filter(|owner| owner.token == token)
+            .map_or(0, |owner| owner.bytes)
+    };
+
+    let mut freed = 0;
+    for (name, _) in library.index.snapshot() {
+        if !snapshot.files.contains_key(&name) {
+            freed += owned(&name);
+        }
+    }
+    let mut added = 0;
+    for (name, file) in &snapshot.files {
+        match library.index.get(name) {
+            Some(entry) if protocol::content_hash(&entry.data) == file.hash => {}
+            Some(_) => {
+                freed += owned(name);
+                added += file.size;
+            }
+            None => added += file.size,
+        }
+    }
+    (freed, added)
+}
+
+/// A file as it was when the snapshot was taken
+#[get("/snapshots/{id}/files/{path:.*}")]
+async fn snapshot_file_get(
+    state: web::Data<AppState>,
+    path: web::Path<SnapshotFilePath>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ServerError> {
+    let library = state.libraries.authorize(&req, Access::Read)?.library;
+    let SnapshotFilePath { id, path: name } = path.into_inner();
+
+    let snapshot = state
+        .snapshots
+        .load(&library.name, id)
+        .await
+        .map_err(ServerError::Io)?
+        .ok_or(ServerError::NotFound)?;
+    let file = snapshot.files.get(&name).ok_or(ServerError::NotFound)?;
+    let data = state.snapshots.read(file).await.map_err(ServerError::Io)?;
+
+    Ok(HttpResponse::Ok().content_type(mime_type(&name)).body(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    use crate::trash::Retention;
+
+    #[actix_web::test]
+    async fn test_snapshots() {
+        let root =
+            std::env::temp_dir().join(format!("music_sync_snapshots_{}", std::process::id()));
+        let music_dir = root.join("music");
+        std::fs::create_dir_all(&music_dir).unwrap();
+        let dir = music_dir.to_str().unwrap();
+        let snapshots = Snapshots::new(root.join("snapshots"));
+        let trash = Trash::new(root.join("trash"), Retention::default());
+
+        std::fs::write(music_dir.join("a.mp3"), "a").unwrap();
+        std::fs::write(music_dir.join("b.mp3"), "b").unwrap();
+        let first = snapshots
+            .create("music", dir, NonUtf8Names::default())
+            .await
+            .unwrap();
+        assert_eq!(first.files.len(), 2);
+
+        // unchanged files share their object with the earlier snapshot
+        std::fs::write(music_dir.join("b.mp3"), "b2").unwrap();
+        std::fs::write(music_dir.join("c.mp3"), "c").unwrap();
+        let second = snapshots
+            .create("music", dir, NonUtf8Names::default())
+            .await
+            .unwrap();
+        assert_eq!(second.files["a.mp3"], first.files["a.mp3"]);
+        let objects = walk_count(&root.join("snapshots").join("objects"));
+        assert_eq!(objects, 4);
+
+        let list = snapshots.list("music").await.unwrap();
+        assert_eq!(
+            list.iter().map(|info| info.id).collect::<Vec<_>>(),
+            [first.id, second.id]
+        );
+        assert_eq!(list[1].bytes, 4);
+
+        let library = Library::load("music", dir, NonUtf8Names::default()).unwrap();
+        let usage = Usage::load(root.join("usage.json")).unwrap();
+        let restored = snapshots
+            .restore(&first, &library, &trash, &usage, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            restored,
+            Restored {
+                written: 1,
+                removed: 1,
+                unchanged: 1
+            }
+        );
+        assert!(matches(&music_dir.join("b.mp3"), &first.files["b.mp3"]));
+        assert!(!music_dir.join("c.mp3").exists());
+        assert_eq!(library.index.get("b.mp3").unwrap().data, b"b");
+        assert!(!library.index.contains("c.mp3"));
+
+        // what the restore replaced or removed can still be restored from the trash
+        assert_eq!(trash.versions("music", None).await.unwrap().len(), 2);
+
+        // a manifest whose hash isn't one can't point outside the objects
+        let path = snapshots.manifest_path("music", first.id);
+        let manifest = std::fs::read_to_string(&path).unwrap();
+        let hash = &first.files["a.mp3"].hash;
+        std::fs::write(&path, manifest.replace(hash.as_str(), "../x")).unwrap();
+        let err = snapshots.load("music", first.id).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_restore_post() {
+        use crate::libraries::Grant;
+        use actix_web::{test, App};
+        use utils::encryption::TokenVerifier;
+
+        let root = std::env::temp_dir().join(format!(
+            "music_sync_snapshot_restore_{}",
+            std::process::id()
+        ));
+        let music_dir = root.join("music");
+        std::fs::create_dir_all(&music_dir).unwrap();
+        std::fs::write(music_dir.join("a.mp3"), "a").unwrap();
+        let state = AppState::for_tests(
+            &root,
+            &["music"],
+            vec![
+                Grant::all("admin"),
+                Grant::parse("guest music:read").unwrap(),
+                Grant::parse("phone music name=phone max_bytes=3").unwrap(),
+            ],
+        );
+        let snapshot = state
+            .snapshots
+            .create(
+                "music",
+                music_dir.to_str().unwrap(),
+                NonUtf8Names::default(),
+            )
+            .await
+            .unwrap();
+        let library = state.libraries.iter().next().unwrap();
+        std::fs::remove_file(music_dir.join("a.mp3")).unwrap();
+        library.index.remove("a.mp3");
+        let big_path = music_dir.join("big.mp3");
+        std::fs::write(&big_path, "bbbbbbbbbb").unwrap();
+        library.index.insert(
+            "big.mp3".to_string(),
+            Entry::new(
+                "big.mp3",
+                &big_path,
+                b"bbbbbbbbbb".to_vec(),
+                SystemTime::now(),
+            ),
+        );
+        let owner = Owner {
+            token: "phone".to_string(),
+            bytes: 3,
+        };
+        assert!(state
+            .usage
+            .try_set_owner("other", "x.mp3", Some(owner), None));
+
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(snapshot_restore_post)
+                .service(crate::files::file_get),
+        )
+        .await;
+        let restore = |token: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/snapshots/{}/restore", snapshot.id))
+                .insert_header((
+                    "Authorization",
+                    TokenVerifier::new(token).encrypt(token.as_bytes()),
+                ))
+                .to_request()
+        };
+
+        let response = test::call_service(&app, restore("guest")).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::FORBIDDEN);
+
+        // removing a file written by another token gives this one no room
+        let response = test::call_service(&app, restore("phone")).await;
+        assert_eq!(
+            response.status(),
+            actix_web::http::StatusCode::INSUFFICIENT_STORAGE
+        );
+        assert_eq!(state.usage.get("phone"), 3);
+
+        // the running server serves the restored file right away
+        let response = test::call_service(&app, restore("admin")).await;
+        assert!(response.status().is_success());
+        let req = test::TestRequest::get()
+            .uri("/files/a.mp3")
+            .insert_header((
+                "Authorization",
+                TokenVerifier::new("guest").encrypt(b"guest"),
+            ))
+            .to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "a");
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    /// Whether `path` holds exactly the content of `file`
+    fn matches(path: &Path, file: &SnapshotFile) -> bool {
+        std::fs::read(path).is_ok_and(|data| protocol::content_hash(&data) == file.hash)
+    }
+
+    fn walk_count(dir: &Path) -> usize {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    walk_count(&path)
+                } else {
+                    1
+                }
+            })
+            .sum()
+    }
+}
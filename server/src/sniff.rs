@@ -0,0 +1,434 @@
+use crate::files::extension;
+
+/// How far into a file its signature is looked for, MP3 files can start with padding
+const SNIFF_LENGTH: usize = 4096;
+
+/// A kind of file the server accepts, recognized by its extension and its first bytes
+pub struct Format {
+    pub name: &'static str,
+    extensions: &'static [&'static str],
+    /// Files that come with the tracks, only accepted with `allow_sidecars`
+    sidecar: bool,
+    matches: fn(&[u8]) -> bool,
+}
+
+pub const FORMATS: [Format; 11] = [
+    Format {
+        name: "mp3",
+        extensions: &["mp3"],
+        sidecar: false,
+        matches: is_mp3,
+    },
+    Format {
+        name: "flac",
+        extensions: &["flac"],
+        sidecar: false,
+        matches: |data| skip_id3(data).starts_with(b"fLaC"),
+    },
+    Format {
+        name: "ogg",
+        extensions: &["ogg", "oga"],
+        sidecar: false,
+        matches: |data| data.starts_with(b"OggS"),
+    },
+    Format {
+        name: "opus",
+        extensions: &["opus"],
+        sidecar: false,
+        matches: |data| data.starts_with(b"OggS") && data.get(28..36) == Some(b"OpusHead"),
+    },
+    Format {
+        name: "wav",
+        extensions: &["wav"],
+        sidecar: false,
+        matches: |data| data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WAVE"),
+    },
+    Format {
+        name: "m4a",
+        extensions: &["m4a", "m4b", "mp4"],
+        sidecar: false,
+        matches: |data| data.get(4..8) == Some(b"ftyp"),
+    },
+    Format {
+        name: "playlist",
+        extensions: &["m3u", "m3u8", "pls"],
+        sidecar: false,
+        matches: is_text,
+    },
+    Format {
+        name: "lrc",
+        extensions: &["lrc"],
+        sidecar: true,
+        matches: is_text,
+    },
+    Format {
+        name: "cue",
+        extensions: &["cue"],
+        sidecar: true,
+        matches: is_text,
+    },
+    Format {
+        name: "jpeg",
+        extensions: &["jpg", "jpeg"],
+        sidecar: true,
+        matches: |data| data.starts_with(&[0xFF, 0xD8, 0xFF]),
+    },
+    Format {
+        name: "png",
+        extensions: &["png"],
+        sidecar: true,
+        matches: |data| data.starts_with(b"\x89PNG\r\n\x1a\n"),
+    },
+];
+
+/// The ID3v2 tag some files start with, whatever their format
+fn skip_id3(data: &[u8]) -> &[u8] {
+    if !data.starts_with(b"ID3") || data.len() < 10 {
+        return data;
+    }
+
+    // the size is syncsafe, 7 bits per byte, and doesn't count the header or footer
+    let size = data[6..10]
+        .iter()
+        .fold(0usize, |size, byte| size << 7 | (byte & 0x7F) as usize);
+    let footer = if data[5] & 0x10 != 0 { 10 } else { 0 };
+    data.get(10 + size + footer..).unwrap_or_default()
+}
+
+/// The bitrates in kbit/s by bitrate index, for MPEG-1 layers I, II, III and MPEG-2
+/// layer I, then II and III. Index 0 is the free format, whose frame length can't be
+/// known from the header.
+const BITRATES: [[u16; 15]; 5] = [
+    [
+        0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+    ],
+    [
+        0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+    ],
+    [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ],
+    [
+        0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+    ],
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+
+/// The length of the MPEG audio frame starting with this header, `None` if it isn't one
+fn frame_length(header: &[u8]) -> Option<usize> {
+    let &[sync, flags, rates, _] = header.get(..4)? else {
+        return None;
+    };
+    let (version, layer) = (flags >> 3 & 0b11, flags >> 1 & 0b11);
+    let (bitrate, sample_rate, padding) = (rates >> 4, rates >> 2 & 0b11, rates >> 1 & 1);
+    if sync != 0xFF
+        || flags & 0xE0 != 0xE0
+        || version == 0b01
+        || layer == 0b00
+        || bitrate == 0b0000
+        || bitrate == 0b1111
+        || sample_rate == 0b11
+    {
+        return None;
+    }
+
+    let mpeg1 = version == 0b11;
+    let table = match (mpeg1, layer) {
+        (true, 0b11) => 0,
+        (true, 0b10) => 1,
+        (true, _) => 2,
+        (false, 0b11) => 3,
+        (false, _) => 4,
+    };
+    let bitrate = BITRATES[table][bitrate as usize] as usize * 1000;
+    let sample_rate = [44100, 48000, 32000][sample_rate as usize]
+        >> match version {
+            0b11 => 0,
+            0b10 => 1,
+            _ => 2,
+        };
+    let padding = padding as usize;
+
+    Some(match layer {
+        0b11 => (12 * bitrate / sample_rate + padding) * 4,
+        0b01 if !mpeg1 => 72 * bitrate / sample_rate + padding,
+        _ => 144 * bitrate / sample_rate + padding,
+    })
+}
+
+/// An MPEG audio frame right after the ID3 tag and any zero padding, followed by a
+/// second one where the first ends
+fn is_mp3(data: &[u8]) -> bool {
+    let data = skip_id3(data);
+    let Some(start) = data[..data.len().min(SNIFF_LENGTH)]
+        .iter()
+        .position(|byte| *byte != 0)
+    else {
+        return false;
+    };
+
+    frame_length(&data[start..])
+        .is_some_and(|length| data.get(start + length..).and_then(frame_length).is_some())
+}
+
+/// Text files have no NUL bytes, binary ones almost always do
+fn is_text(data: &[u8]) -> bool {
+    !data[..data.len().min(SNIFF_LENGTH)].contains(&0)
+}
+
+/// The formats uploads may have, set with `allowed_types` and `allow_sidecars`. Every
+/// file is accepted unless `allowed_types` is set, a library may hold formats that
+/// aren't sniffed and they would stop being synced.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Allowlist {
+    /// `None` accepts any file without looking at it
+    types: Option<Vec<&'static str>>,
+    pub sidecars: bool,
+}
+
+impl Allowlist {
+    /// `any`, `audio` for every audio format and playlists, or a comma separated list of
+    /// format names like `mp3, flac, playlist`
+    pub fn set_types(&mut self, value: &str) -> bool {
+        match value.trim() {
+            "any" => {
+                self.types = None;
+                return true;
+            }
+            "audio" => {
+                let types = FORMATS.iter().filter(|format| !format.sidecar);
+                self.types = Some(types.map(|format| format.name).collect());
+                return true;
+            }
+            _ => {}
+        }
+
+        let types = value
+            .split(',')
+            .map(|name| {
+                FORMATS
+                    .iter()
+                    .find(|format| format.name == name.trim())
+                    .map(|format| format.name)
+            })
+            .collect::<Option<Vec<_>>>();
+        match types {
+            Some(types) if !types.is_empty() => {
+                self.types = Some(types);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn format(&self, name: &str) -> Option<&'static Format> {
+        let extension = extension(name);
+        FORMATS.iter().find(|format| {
+            format.extensions.contains(&extension.as_str())
+                && (format.sidecar && self.sidecars
+                    || self
+                        .types
+                        .as_ref()
+                        .is_some_and(|types| types.contains(&format.name)))
+        })
+    }
+
+    /// Whether a file with this name could be accepted, before its content is known
+    pub fn allows_name(&self, name: &str) -> bool {
+        self.types.is_none() || self.format(name).is_some()
+    }
+
+    /// Why the file isn't accepted: its extension isn't allowed, or its first bytes
+    /// don't match its extension
+    pub fn check(&self, name: &str, data: &[u8]) -> Result<(), String> {
+        if self.types.is_none() {
+            return Ok(());
+        }
+
+        let Some(format) = self.format(name) else {
+            return Err(match extension(name).as_str() {
+                "" => "files without an extension aren't allowed on this server".to_string(),
+                extension => format!(".{} files aren't allowed on this server", extension),
+            });
+        };
+        if !(format.matches)(data) {
+            return Err(format!(
+                "the content doesn't look like a .{} file",
+                extension(name)
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Two silent MPEG-1 layer III frames, with `tag` at the start of the first one's data
+#[cfg(test)]
+pub fn mp3(tag: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for _ in 0..2 {
+        let start = data.len();
+        data.extend_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+        data.extend_from_slice(tag);
+        data.resize(start + 417, 0);
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff() {
+        let mut allowlist = Allowlist::default();
+        assert_eq!(allowlist.check("song.aiff", b"FORM"), Ok(()));
+        assert!(allowlist.allows_name("song.webm"));
+        assert!(allowlist.set_types("audio"));
+
+        let mut flac = b"ID3\x03\x00\x00\x00\x00\x00\x02tgfLaC".to_vec();
+        assert_eq!(allowlist.check("song.flac", &flac), Ok(()));
+        flac[12] = b'X';
+        assert_eq!(
+            allowlist.check("song.flac", &flac),
+            Err("the content doesn't look like a .flac file".to_string())
+        );
+
+        let mut padded = vec![0; 2];
+        padded.extend_from_slice(&mp3(b""));
+        assert_eq!(allowlist.check("song.MP3", &padded), Ok(()));
+        assert!(allowlist.check("song.mp3", b"MZ\x90\x00").is_err());
+        assert_eq!(allowlist.check("song.m4a", b"\0\0\0\x20ftypM4A "), Ok(()));
+        assert_eq!(
+            allowlist.check("song.wav", b"RIFF\x24\0\0\0WAVEfmt "),
+            Ok(())
+        );
+
+        let mut opus = b"OggS".to_vec();
+        opus.resize(28, 0);
+        assert!(allowlist.check("song.opus", &opus).is_err());
+        opus.extend_from_slice(b"OpusHead");
+        assert_eq!(allowlist.check("song.opus", &opus), Ok(()));
+        assert_eq!(allowlist.check("song.ogg", &opus), Ok(()));
+
+        assert_eq!(allowlist.check("list.m3u", b"#EXTM3U\nsong.mp3\n"), Ok(()));
+        assert!(allowlist.check("list.m3u", b"\0\0binary").is_err());
+
+        assert_eq!(
+            allowlist.check("setup.exe", b"MZ"),
+            Err(".exe files aren't allowed on this server".to_string())
+        );
+        assert!(!allowlist.allows_name("cover.jpg"));
+    }
+
+    #[test]
+    fn test_mp3() {
+        let data = mp3(b"episode");
+        assert_eq!(frame_length(&data), Some(417));
+        assert!(is_mp3(&data));
+        // a single frame, or one whose length points past the second header
+        assert!(!is_mp3(&data[..417]));
+        assert!(!is_mp3(&data[1..]));
+
+        let mut tagged = b"ID3\x03\x00\x00\x00\x00\x00\x04tag!".to_vec();
+        tagged.extend_from_slice(&data);
+        assert!(is_mp3(&tagged));
+
+        // reserved sample rate
+        let mut reserved = data.clone();
+        reserved[2] = 0x9C;
+        assert!(!is_mp3(&reserved));
+
+        assert!(!is_mp3(b"ID3"));
+        assert!(!is_mp3(b"ID3episode"));
+        let mut garbage = b"ID3\x03\x00\x00\x00\x00\x00\x00".to_vec();
+        garbage.extend((0..4096u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8));
+        assert!(!is_mp3(&garbage));
+    }
+
+    #[test]
+    fn test_mp3_random() {
+        // xorshift, so the blocks are the same on every run
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let accepted = (0..500)
+            .filter(|_| {
+                let block = (0..4096)
+                    .map(|_| {
+                        state ^= state << 13;
+                        state ^= state >> 7;
+                        state ^= state << 17;
+                        state as u8
+                    })
+                    .collect::<Vec<_>>();
+                is_mp3(&block)
+            })
+            .count();
+        assert_eq!(accepted, 0);
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let mut allowlist = Allowlist::default();
+        assert!(allowlist.set_types("flac, playlist"));
+        assert!(!allowlist.set_types("flac, exe"));
+        assert!(!allowlist.allows_name("song.mp3"));
+        assert!(allowlist.allows_name("song.flac"));
+
+        allowlist.sidecars = true;
+        assert_eq!(
+            allowlist.check("cover.jpg", &[0xFF, 0xD8, 0xFF, 0xE0]),
+            Ok(())
+        );
+        assert_eq!(allowlist.check("song.lrc", b"[00:01.00]Hello"), Ok(()));
+        assert!(allowlist.check("cover.png", b"not a png").is_err());
+
+        assert!(allowlist.set_types("any"));
+        assert_eq!(allowlist.check("setup.exe", b"MZ"), Ok(()));
+    }
+
+    #[actix_web::test]
+    async fn test_upload_sniffing() {
+        use crate::{libraries::Grant, sync_post, upload_body, AppState, DEFAULT_LIBRARY};
+        use actix_web::{test, App};
+        use utils::{
+            encryption::TokenVerifier,
+            protocol::{SyncResponse, UploadResult},
+        };
+
+        let root = std::env::temp_dir().join(format!("music_sync_sniff_{}", std::process::id()));
+        let state = AppState::for_tests(&root, &[DEFAULT_LIBRARY], vec![Grant::all("admin")]);
+        let app = test::init_service(App::new().app_data(state).service(sync_post)).await;
+
+        // files that aren't what their extension says are rejected one by one
+        let req = test::TestRequest::post()
+            .uri("/sync")
+            .insert_header((
+                "Authorization",
+                TokenVerifier::new("admin").encrypt(b"admin"),
+            ))
+            .set_payload(upload_body(&[
+                ("a.mp3", &mp3(b"a")),
+                ("c.exe", b"MZ"),
+                ("d.mp3", b"MZ"),
+            ]))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let uploads = SyncResponse::read(&mut body.as_ref()).unwrap().uploads;
+        assert_eq!(uploads["a.mp3"], UploadResult::Written);
+        assert_eq!(
+            uploads["c.exe"],
+            UploadResult::Rejected {
+                reason: ".exe files aren't allowed on this server".to_string()
+            }
+        );
+        assert_eq!(
+            uploads["d.mp3"],
+            UploadResult::Rejected {
+                reason: "the content doesn't look like a .mp3 file".to_string()
+            }
+        );
+        assert!(!root.join(DEFAULT_LIBRARY).join("d.mp3").exists());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::{web::Bytes, HttpRequest};
+use futures::Stream;
+use utils::bandwidth::{Limiter, Schedule, CHUNK_SIZE};
+
+/// A device that hasn't transferred anything for this long has a full bucket again, so
+/// its limiter can make way for a new one
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A bandwidth limit for one direction, every device gets its own bucket so a busy one
+/// doesn't slow the others down
+pub struct Throttle {
+    schedule: Schedule,
+    /// The limiter of every device, and when it was last used
+    devices: Mutex<HashMap<IpAddr, (Arc<Limiter>, Instant)>>,
+}
+
+impl Throttle {
+    pub fn new(schedule: Schedule) -> Self {
+        Self {
+            schedule,
+            devices: Mutex::default(),
+        }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.schedule.is_unlimited()
+    }
+
+    /// Waits until `bytes` may be transferred to or from `device`
+    pub async fn wait(&self, device: IpAddr, bytes: u64) {
+        if self.is_unlimited() {
+            return;
+        }
+
+        let limiter = {
+            let mut devices = self.devices.lock().unwrap();
+            let now = Instant::now();
+            // the idle devices are dropped when a new one comes, otherwise every address
+            // ever seen would keep its limiter
+            if !devices.contains_key(&device) {
+                devices.retain(|_, (limiter, used)| {
+                    Arc::strong_count(limiter) > 1 || now.duration_since(*used) < IDLE_TIMEOUT
+                });
+            }
+            let (limiter, used) = devices
+                .entry(device)
+                .or_insert_with(|| (Arc::new(Limiter::new(self.schedule.clone())), now));
+            *used = now;
+            limiter.clone()
+        };
+
+        let delay = limiter.delay(bytes);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Streams `body` in chunks, as fast as the limit for `device` allows
+    pub fn stream(
+        self: Arc<Self>,
+        device: IpAddr,
+        body: Vec<u8>,
+    ) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+        let body = Bytes::from(body);
+
+        futures::stream::unfold(0, move |offset| {
+            let (throttle, body) = (self.clone(), body.clone());
+            async move {
+                if offset >= body.len() {
+                    return None;
+                }
+
+                let end = body.len().min(offset + CHUNK_SIZE);
+                throttle.wait(device, (end - offset) as u64).await;
+                Some((Ok(body.slice(offset..end)), end))
+            }
+        })
+    }
+}
+
+/// Devices are told apart by their address, the clients behind one address share a limit
+pub fn device(req: &HttpRequest) -> IpAddr {
+    req.peer_addr()
+        .map(|addr| addr.ip())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_stream() {
+        let throttle = Arc::new(Throttle::new(Schedule::parse("64K").unwrap()));
+        let device = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        let start = Instant::now();
+        let chunks: Vec<_> = throttle
+            .clone()
+            .stream(device, vec![1; 96 * 1024])
+            .collect()
+            .await;
+        assert_eq!(chunks.len(), 6);
+        assert!(chunks
+            .iter()
+            .all(|chunk| chunk.as_ref().unwrap().len() == CHUNK_SIZE));
+        // the first second worth goes through at once, the rest at 64K/s
+        assert!(start.elapsed() >= Duration::from_millis(450));
+
+        // other devices have their own bucket
+        let start = Instant::now();
+        throttle
+            .wait(IpAddr::V4(Ipv4Addr::BROADCAST), 64 * 1024)
+            .await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[actix_web::test]
+    async fn test_idle_devices() {
+        let throttle = Throttle::new(Schedule::parse("64K").unwrap());
+        let idle = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let busy = IpAddr::V4(Ipv4Addr::BROADCAST);
+        throttle.wait(idle, 1).await;
+        throttle.wait(busy, 1).await;
+        throttle.devices.lock().unwrap().get_mut(&idle).unwrap().1 =
+            Instant::now() - IDLE_TIMEOUT * 2;
+
+        // a new device sweeps out the ones that went quiet
+        throttle.wait(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 1).await;
+        let devices = throttle.devices.lock().unwrap();
+        assert_eq!(devices.len(), 2);
+        assert!(!devices.contains_key(&idle));
+    }
+}
@@ -0,0 +1,524 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use utils::{
+    file_names,
+    protocol::{FileVersion, TrashReason},
+};
+
+use crate::{error::ServerError, index::Entry, libraries::Access, quota::Owner, AppState};
+
+pub const DEFAULT_TRASH_DIR: &str = "trash";
+pub const DEFAULT_KEEP_VERSIONS: usize = 10;
+pub const DEFAULT_KEEP_DAYS: u64 = 30;
+/// How often versions older than `trash_days` are looked for
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long replaced and deleted files are kept, set with `trash_versions` and
+/// `trash_days`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Retention {
+    /// The versions kept of each file, 0 turns the trash off
+    pub versions: usize,
+    /// `None` keeps them until there are too many
+    pub days: Option<u64>,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            versions: DEFAULT_KEEP_VERSIONS,
+            days: Some(DEFAULT_KEEP_DAYS),
+        }
+    }
+}
+
+impl Retention {
+    /// Whether the version at `position`, 0 being the newest one, has to go
+    fn expired(&self, position: usize, version: u64, now: SystemTime) -> bool {
+        let age = now
+            .duration_since(UNIX_EPOCH + Duration::from_millis(version))
+            .unwrap_or_default();
+        position >= self.versions
+            || self
+                .days
+                .is_some_and(|days| age > Duration::from_secs(days * 24 * 60 * 60))
+    }
+}
+
+/// Where the files that were overwritten or deleted go, outside of the libraries so they
+/// aren't synced. Every version is a file named after when it was moved and why, in a
+/// directory named after the file: `trash/{library}/{name}/{version}.{reason}`.
+pub struct Trash {
+    dir: PathBuf,
+    retention: Retention,
+}
+
+impl Trash {
+    pub fn new(dir: impl Into<PathBuf>, retention: Retention) -> Self {
+        Self {
+            dir: dir.into(),
+            retention,
+        }
+    }
+
+    fn library_dir(&self, library: &str) -> PathBuf {
+        self.dir.join(library)
+    }
+
+    fn versions_dir(&self, library: &str, name: &str) -> Option<PathBuf> {
+        file_names::path(self.library_dir(library).to_str()?, name)
+    }
+
+    /// Takes the file at `path` out of the library, into the trash unless it's turned
+    /// off. Returns where it went, to put it back if what replaces it can't be written.
+    pub async fn keep(
+        &self,
+        library: &str,
+        name: &str,
+        path: &Path,
+        reason: TrashReason,
+    ) -> io::Result<Option<PathBuf>> {
+        if self.retention.versions == 0 {
+            return match reason {
+                TrashReason::Replaced => Ok(None),
+                TrashReason::Deleted => tokio::fs::remove_file(path).await.map(|()| None),
+            };
+        }
+
+        let dir = self
+            .versions_dir(library, name)
+            .ok_or_else(|| io::Error::other("the name can't be used on this system"))?;
+        tokio::fs::create_dir_all(&dir).await?;
+
+        // two versions moved in the same millisecond get consecutive ones
+        let mut version = now_millis();
+        while self.find(&dir, version).await? {
+            version += 1;
+        }
+        let version_path = dir.join(format!("{}.{}", version, reason));
+
+        move_file(path, &version_path).await?;
+        self.prune_versions(&dir, SystemTime::now()).await?;
+        Ok(Some(version_path))
+    }
+
+    /// The versions of every file of the library, or of only `name`, by name and newest
+    /// first
+    pub async fn versions(
+        &self,
+        library: &str,
+        name: Option<&str>,
+    ) -> io::Result<Vec<FileVersion>> {
+        let dirs = match name {
+            Some(name) => match self.versions_dir(library, name) {
+                Some(dir) => vec![(name.to_string(), dir)],
+                None => Vec::new(),
+            },
+            None => {
+                let mut dirs = Vec::new();
+                let mut entries = match tokio::fs::read_dir(self.library_dir(library)).await {
+                    Ok(entries) => entries,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+                    Err(err) => return Err(err),
+                };
+                while let Some(entry) = entries.next_entry().await? {
+                    let name = utils::normalize_name(&file_names::encode(&entry.file_name()));
+                    dirs.push((name, entry.path()));
+                }
+                dirs
+            }
+        };
+
+        let mut versions = Vec::new();
+        for (name, dir) in dirs {
+            for (version, reason, path) in read_versions(&dir).await? {
+                let size = tokio::fs::metadata(path).await?.len();
+                versions.push(FileVersion {
+                    name: name.clone(),
+                    version,
+                    reason,
+                    size,
+                });
+            }
+        }
+        versions.sort_by(|a, b| a.name.cmp(&b.name).then(b.version.cmp(&a.version)));
+        Ok(versions)
+    }
+
+    /// The file holding a version, if it's still kept
+    pub async fn version_path(
+        &self,
+        library: &str,
+        name: &str,
+        version: u64,
+    ) -> io::Result<Option<PathBuf>> {
+        let Some(dir) = self.versions_dir(library, name) else {
+            return Ok(None);
+        };
+        Ok(read_versions(&dir)
+            .await?
+            .into_iter()
+            .find(|(other, _, _)| *other == version)
+            .map(|(_, _, path)| path))
+    }
+
+    async fn find(&self, dir: &Path, version: u64) -> io::Result<bool> {
+        Ok(read_versions(dir)
+            .await?
+            .iter()
+            .any(|(other, _, _)| *other == version))
+    }
+
+    /// Removes the versions of every file of the library that are too old, returns how
+    /// many were removed
+    pub async fn prune(&self, library: &str) -> io::Result<usize> {
+        let mut entries = match tokio::fs::read_dir(self.library_dir(library)).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err),
+        };
+
+        let now = SystemTime::now();
+        let mut removed = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            removed += self.prune_versions(&entry.path(), now).await?;
+        }
+        Ok(removed)
+    }
+
+    async fn prune_versions(&self, dir: &Path, now: SystemTime) -> io::Result<usize> {
+        let versions = read_versions(dir).await?;
+
+        let mut removed = 0;
+        for (position, (version, _, path)) in versions.iter().enumerate() {
+            if self.retention.expired(position, *version, now) {
+                tokio::fs::remove_file(path).await?;
+                removed += 1;
+            }
+        }
+        if removed == versions.len() {
+            // another version may have been moved in meanwhile, then it stays
+            let _ = tokio::fs::remove_dir(dir).await;
+        }
+        Ok(removed)
+    }
+}
+
+/// The versions in the directory of a file, newest first
+async fn read_versions(dir: &Path) -> io::Result<Vec<(u64, TrashReason, PathBuf)>> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut versions = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name();
+        let Some((version, reason)) = file_name.to_str().and_then(|name| name.split_once('.'))
+        else {
+            continue;
+        };
+        let reason = match reason {
+            "replaced" => TrashReason::Replaced,
+            "deleted" => TrashReason::Deleted,
+            _ => continue,
+        };
+        if let Ok(version) = version.parse::<u64>() {
+            versions.push((version, reason, entry.path()));
+        }
+    }
+    versions.sort_by_key(|(version, _, _)| std::cmp::Reverse(*version));
+    Ok(versions)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Renames the file, or copies it when the trash is on another file system
+pub async fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if tokio::fs::rename(from, to).await.is_ok() {
+        return Ok(());
+    }
+    tokio::fs::copy(from, to).await?;
+    tokio::fs::remove_file(from).await
+}
+
+#[derive(Deserialize)]
+struct TrashQuery {
+    name: Option<String>,
+}
+
+/// The versions kept in the trash of the library, `name` only lists those of one file
+#[get("/trash")]
+async fn trash_get(
+    state: web::Data<AppState>,
+    query: web::Query<TrashQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ServerError> {
+    let library = state.libraries.authorize(&req, Access::Read)?.library;
+
+    let name = query.name.as_deref().map(utils::normalize_name);
+    let versions = state
+        .trash
+        .versions(&library.name, name.as_deref())
+        .await
+        .map_err(ServerError::Io)?;
+    Ok(HttpResponse::Ok().json(versions))
+}
+
+#[derive(Deserialize)]
+struct RestoreQuery {
+    name: String,
+    version: u64,
+}
+
+/// Puts a version back in the library. The file it replaces goes to the trash, so a
+/// restore can be undone like any other change.
+#[post("/trash/restore")]
+async fn restore_post(
+    state: web::Data<AppState>,
+    query: web::Query<RestoreQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ServerError> {
+    let authorized = state.libraries.authorize(&req, Access::Write)?;
+    let (library, grant) = (authorized.library, authorized.grant);
+    let RestoreQuery { name, version } = query.into_inner();
+    let name = utils::normalize_name(&name);
+
+    let _lock = library.index.lock_path(&name).await;
+
+    let version_path = state
+        .trash
+        .version_path(&library.name, &name, version)
+        .await
+        .map_err(ServerError::Io)?
+        .ok_or(ServerError::NotFound)?;
+    let path = library.path(&name).ok_or(ServerError::NotFound)?;
+    let reason = match version_path.extension().and_then(|reason| reason.to_str()) {
+        Some("deleted") => TrashReason::Deleted,
+        _ => TrashReason::Replaced,
+    };
+    let data = tokio::fs::read(&version_path)
+        .await
+        .map_err(ServerError::Io)?;
+    let size = data.len() as u64;
+
+    // the restored version is counted like an upload by the token restoring it, the one
+    // that wrote the current file gets its bytes back
+    let replaced = library.index.contains(&name);
+    let previous = state.usage.owner(&library.name, &name);
+    let owner = grant.name.as_ref().map(|user| Owner {
+        token: user.clone(),
+        bytes: size,
+    });
+    let max_bytes = grant.quota.max_bytes;
+    if !state
+        .usage
+        .try_set_owner(&library.name, &name, owner, max_bytes)
+    {
+        let used = grant.name.as_ref().map_or(0, |user| state.usage.get(user));
+        return Err(ServerError::OverQuota(used, max_bytes.unwrap_or_default()));
+    }
+
+    // the current file may push the restored version out of the retention, so it's read
+    // before and removed after
+    let written = async {
+        if replaced {
+            state
+                .trash
+                .keep(&library.name, &name, &path, TrashReason::Replaced)
+                .await?;
+        }
+        tokio::fs::write(&path, &data).await
+    };
+    if let Err(err) = written.await {
+        state
+            .usage
+            .try_set_owner(&library.name, &name, previous, None);
+        return Err(ServerError::Io(err));
+    }
+    if let Err(err) = state.usage.save().await {
+        eprintln!("Failed to save the usage of the tokens: {}", err);
+    }
+    library.index.insert(
+        name.clone(),
+        Entry::new(&name, &path, data, SystemTime::now()),
+    );
+    if let Err(err) = tokio::fs::remove_file(&version_path).await {
+        if err.kind() != io::ErrorKind::NotFound {
+            eprintln!(
+                "Failed to remove the restored version of {:?}: {}",
+                name, err
+            );
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(FileVersion {
+        name,
+        version,
+        reason,
+        size,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_trash() {
+        let root = std::env::temp_dir().join(format!("music_sync_trash_{}", std::process::id()));
+        let music_dir = root.join("music");
+        std::fs::create_dir_all(&music_dir).unwrap();
+        let trash = Trash::new(
+            root.join("trash"),
+            Retention {
+                versions: 2,
+                days: Some(1),
+            },
+        );
+
+        let path = music_dir.join("song.mp3");
+        for data in ["first", "second", "third"] {
+            std::fs::write(&path, data).unwrap();
+            let kept = trash
+                .keep("music", "song.mp3", &path, TrashReason::Replaced)
+                .await
+                .unwrap();
+            assert!(kept.is_some());
+            assert!(!path.exists());
+        }
+
+        // only the two newest versions are kept
+        let versions = trash.versions("music", None).await.unwrap();
+        assert_eq!(versions.len(), 2);
+        assert!(versions[0].version > versions[1].version);
+        assert_eq!(versions[0].reason, TrashReason::Replaced);
+        let newest = trash
+            .version_path("music", "song.mp3", versions[0].version)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(std::fs::read(newest).unwrap(), b"third");
+        assert!(trash
+            .versions("music", Some("other.mp3"))
+            .await
+            .unwrap()
+            .is_empty());
+
+        // and only for as long as the retention allows
+        let dir = trash.versions_dir("music", "song.mp3").unwrap();
+        std::fs::write(dir.join("1000.deleted"), "old").unwrap();
+        assert_eq!(trash.prune("music").await.unwrap(), 1);
+        assert_eq!(trash.versions("music", None).await.unwrap().len(), 2);
+
+        // without versions to keep, deleted files are just gone
+        let off = Trash::new(
+            root.join("trash"),
+            Retention {
+                versions: 0,
+                days: None,
+            },
+        );
+        std::fs::write(&path, "data").unwrap();
+        let kept = off
+            .keep("music", "song.mp3", &path, TrashReason::Deleted)
+            .await
+            .unwrap();
+        assert_eq!(kept, None);
+        assert!(!path.exists());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_trash_routes() {
+        use crate::{files::file_delete, libraries::Grant, sniff::mp3, sync_post, upload_body};
+        use crate::{AppState, DEFAULT_LIBRARY};
+        use actix_web::{test, App};
+        use utils::encryption::TokenVerifier;
+
+        let root =
+            std::env::temp_dir().join(format!("music_sync_trash_routes_{}", std::process::id()));
+        let state = AppState::for_tests(
+            &root,
+            &[DEFAULT_LIBRARY],
+            vec![
+                Grant::all("admin"),
+                Grant::parse("guest music:read").unwrap(),
+            ],
+        );
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(sync_post)
+                .service(file_delete)
+                .service(trash_get)
+                .service(restore_post),
+        )
+        .await;
+        let authorization = |token: &str| TokenVerifier::new(token).encrypt(token.as_bytes());
+        let path = root.join(DEFAULT_LIBRARY).join("episode.mp3");
+
+        // replaced and deleted files go to the trash, where they can be restored from
+        for data in [mp3(b"episode"), mp3(b"episode 2")] {
+            let req = test::TestRequest::post()
+                .uri("/sync")
+                .insert_header(("Authorization", authorization("admin")))
+                .set_payload(upload_body(&[("episode.mp3", &data)]))
+                .to_request();
+            assert!(test::call_service(&app, req).await.status().is_success());
+        }
+        let req = test::TestRequest::delete()
+            .uri("/files/episode.mp3")
+            .insert_header(("Authorization", authorization("admin")))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::NO_CONTENT);
+        let library = state.libraries.iter().next().unwrap();
+        assert!(!library.index.contains("episode.mp3"));
+        assert!(!path.exists());
+
+        let req = test::TestRequest::get()
+            .uri("/trash?name=episode.mp3")
+            .insert_header(("Authorization", authorization("guest")))
+            .to_request();
+        let versions: Vec<FileVersion> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[0].reason, TrashReason::Deleted);
+        assert_eq!(versions[1].reason, TrashReason::Replaced);
+
+        // only tokens that can write may restore, and each version only once
+        let restore = |token: &str, version: u64| {
+            test::TestRequest::post()
+                .uri(&format!(
+                    "/trash/restore?name=episode.mp3&version={}",
+                    version
+                ))
+                .insert_header(("Authorization", authorization(token)))
+                .to_request()
+        };
+        let response = test::call_service(&app, restore("guest", versions[1].version)).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::FORBIDDEN);
+        let response = test::call_service(&app, restore("admin", versions[1].version)).await;
+        assert!(response.status().is_success());
+        assert_eq!(std::fs::read(&path).unwrap(), mp3(b"episode"));
+        assert_eq!(
+            library.index.get("episode.mp3").unwrap().data,
+            mp3(b"episode")
+        );
+        let response = test::call_service(&app, restore("admin", versions[1].version)).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::NOT_FOUND);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,260 @@
+use std::{io, time::Duration};
+
+use utils::{
+    bandwidth::Schedule, file_names::NonUtf8Names, filter::SyncFilter, playlist::PathStyle,
+    protocol,
+};
+
+use crate::{planner::Priority, retry::RetryPolicy};
+
+/// Everything a client's `config.conf` sets. The first three lines are the server url,
+/// the token and the music directory, then optional `key = value` settings follow.
+#[derive(Clone)]
+pub struct Config {
+    pub server_url: String,
+    pub token: String,
+    pub music_dir: String,
+    /// The library `music_dir` syncs with, `None` for the server's default one
+    pub library: Option<String>,
+    /// Other libraries to sync, with the directory each one goes to
+    pub libraries: Vec<(String, String)>,
+    pub filter: SyncFilter,
+    pub max_size: Option<u64>,
+    pub priority: Priority,
+    pub favorites: Option<String>,
+    pub rotation_days: u64,
+    pub playlist_style: PathStyle,
+    pub sanitize_names: bool,
+    pub non_utf8_names: NonUtf8Names,
+    pub retry: RetryPolicy,
+    pub concurrent_uploads: usize,
+    pub download_limit: Schedule,
+    pub upload_limit: Schedule,
+}
+
+impl Config {
+    /// Parses the text of a `config.conf`, the settings that were ignored are returned
+    /// as warnings
+    pub fn parse(buffer: &str) -> io::Result<(Self, Vec<String>)> {
+        let mut lines = buffer.lines();
+        let mut next_line = |name: &str| {
+            lines.next().map(str::to_string).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("Missing {}", name))
+            })
+        };
+
+        let server_url = next_line("server_url")?;
+        let token = next_line("token")?;
+        let music_dir = next_line("music_dir")?;
+        let mut warnings = Vec::new();
+
+        // everything after the first three lines is an optional `key = value` setting
+        let mut libraries: Vec<(String, String)> = Vec::new();
+        let mut filter = SyncFilter::default();
+        let mut max_size = None;
+        let mut priority = Priority::Recent;
+        let mut favorites = None;
+        let mut rotation_days = 7;
+        let mut playlist_absolute = false;
+        let mut playlist_root = None;
+        let mut playlist_separator = std::path::MAIN_SEPARATOR;
+        let mut sanitize_names = false;
+        let mut non_utf8_names = NonUtf8Names::default();
+        let mut retry = RetryPolicy::default();
+        let mut concurrent_uploads = 2;
+        let mut download_limit = Schedule::default();
+        let mut upload_limit = Schedule::default();
+
+        for line in lines {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                warnings.push(format!("Ignoring invalid config line: {}", line));
+                continue;
+            };
+
+            // `rule` values contain `=` themselves, so only the first one separates the key
+            let (key, value) = (key.trim(), value.trim());
+            let valid = match key {
+                "library" => match value.split_once(char::is_whitespace) {
+                    Some((name, dir))
+                        if protocol::is_valid_library_name(name)
+                            && libraries.iter().all(|(other, _)| other != name) =>
+                    {
+                        libraries.push((name.to_string(), dir.trim().to_string()));
+                        true
+                    }
+                    _ => false,
+                },
+                "max_size" => {
+                    max_size = utils::parse_size(value);
+                    max_size.is_some()
+                }
+                "priority" => Priority::parse(value)
+                    .map(|value| priority = value)
+                    .is_some(),
+                "favorites" => {
+                    favorites = Some(value.to_string());
+                    true
+                }
+                "rotation_days" => value.parse().map(|value| rotation_days = value).is_ok(),
+                "playlist_paths" => match value {
+                    "absolute" => {
+                        playlist_absolute = true;
+                        true
+                    }
+                    "relative" => {
+                        playlist_absolute = false;
+                        true
+                    }
+                    _ => false,
+                },
+                "sanitize_names" => value.parse().map(|value| sanitize_names = value).is_ok(),
+                "non_utf8_names" => NonUtf8Names::parse(value)
+                    .map(|value| non_utf8_names = value)
+                    .is_some(),
+                "retries" => value.parse().map(|value| retry.retries = value).is_ok(),
+                "retry_delay" => value
+                    .parse()
+                    .map(|value| retry.initial_delay = Duration::from_secs_f64(value))
+                    .is_ok_and(|_| retry.initial_delay > Duration::ZERO),
+                "concurrent_uploads" => match value.parse() {
+                    Ok(value) if value > 0 => {
+                        concurrent_uploads = value;
+                        true
+                    }
+                    _ => false,
+                },
+                "download_limit" => Schedule::parse(value)
+                    .map(|value| download_limit = value)
+                    .is_some(),
+                "upload_limit" => Schedule::parse(value)
+                    .map(|value| upload_limit = value)
+                    .is_some(),
+                "playlist_root" => {
+                    playlist_root = Some(value.to_string());
+                    true
+                }
+                "playlist_separator" => match value {
+                    "/" | "\\" => {
+                        playlist_separator = value.chars().next().unwrap();
+                        true
+                    }
+                    _ => false,
+                },
+                _ => filter.add(key, value),
+            };
+
+            if !valid {
+                warnings.push(format!("Ignoring unknown or invalid setting: {}", line));
+            }
+        }
+
+        // the root is where the library is mounted on the device that plays the playlists,
+        // which is not always where this client writes it
+        let playlist_style = PathStyle {
+            root: playlist_absolute.then(|| playlist_root.unwrap_or_else(|| music_dir.clone())),
+            separator: playlist_separator,
+        };
+
+        let config = Self {
+            server_url,
+            token,
+            music_dir,
+            library: None,
+            libraries,
+            filter,
+            max_size,
+            priority,
+            favorites,
+            rotation_days,
+            playlist_style,
+            sanitize_names,
+            non_utf8_names,
+            retry,
+            concurrent_uploads,
+            download_limit,
+            upload_limit,
+        };
+        Ok((config, warnings))
+    }
+
+    /// The url of `path` on the server, in the library this config syncs
+    pub fn url(&self, path: &str) -> String {
+        match &self.library {
+            Some(library) => format!("{}/libraries/{}/{}", self.server_url, library, path),
+            None => format!("{}/{}", self.server_url, path),
+        }
+    }
+
+    /// A config for every library to sync: this one for the default library unless its
+    /// music directory is left empty, then one for each `library` setting with its own
+    /// directory and the same other settings
+    pub fn per_library(&self) -> Vec<Config> {
+        let mut configs = Vec::new();
+        if !self.music_dir.is_empty() {
+            configs.push(Config {
+                libraries: Vec::new(),
+                ..self.clone()
+            });
+        }
+        for (name, dir) in &self.libraries {
+            configs.push(Config {
+                music_dir: dir.clone(),
+                library: Some(name.clone()),
+                libraries: Vec::new(),
+                ..self.clone()
+            });
+        }
+        configs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let (config, warnings) = Config::parse(
+            "http://localhost:8080\ntoken\n/music\n\n# comment\nmax_size = 32G\nconcurrent_uploads = 0\nretries = 2\nplaylist_paths = absolute\nlibrary = podcasts /sdcard/Podcasts\nlibrary = ../up /tmp\nnonsense",
+        )
+        .unwrap();
+        assert_eq!(config.server_url, "http://localhost:8080");
+        assert_eq!(config.music_dir, "/music");
+        assert_eq!(config.max_size, Some(32 * 1024 * 1024 * 1024));
+        assert_eq!(config.concurrent_uploads, 2);
+        assert_eq!(config.retry.retries, 2);
+        assert_eq!(config.playlist_style.root.as_deref(), Some("/music"));
+        assert_eq!(config.url("sync"), "http://localhost:8080/sync");
+
+        let configs = config.per_library();
+        assert_eq!(configs.len(), 2);
+        assert_eq!(configs[1].music_dir, "/sdcard/Podcasts");
+        assert_eq!(
+            configs[1].url("sync"),
+            "http://localhost:8080/libraries/podcasts/sync"
+        );
+        assert_eq!(configs[1].max_size, config.max_size);
+
+        let (config, _) =
+            Config::parse("http://localhost:8080\ntoken\n\nlibrary = podcasts /podcasts").unwrap();
+        let configs = config.per_library();
+        assert_eq!(configs.len(), 1);
+        assert_eq!(configs[0].library.as_deref(), Some("podcasts"));
+        assert_eq!(
+            warnings,
+            [
+                "Ignoring unknown or invalid setting: concurrent_uploads = 0",
+                "Ignoring unknown or invalid setting: library = ../up /tmp",
+                "Ignoring invalid config line: nonsense"
+            ]
+        );
+
+        let err = Config::parse("http://localhost:8080\ntoken").err().unwrap();
+        assert_eq!(err.to_string(), "Missing music_dir");
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, Take};
+use utils::{
+    file_names,
+    protocol::{SyncResponse, ENVELOPE_PREFIX_SIZE},
+    DiskNames,
+};
+
+use crate::{names::NameMap, Config};
+
+/// The response to `GET /sync` while it arrives. The header is read right away so the
+/// uploads can start, the files are then read one at a time.
+pub struct Envelope<R> {
+    reader: R,
+    names: HashSet<String>,
+}
+
+impl<R: AsyncRead + Unpin> Envelope<R> {
+    pub async fn open(mut reader: R) -> io::Result<(SyncResponse, Self)> {
+        let mut prefix = [0; ENVELOPE_PREFIX_SIZE];
+        reader.read_exact(&mut prefix).await?;
+        let mut header = vec![0; SyncResponse::header_length(&prefix)?];
+        reader.read_exact(&mut header).await?;
+        let response = SyncResponse::from_header(&header)?;
+
+        // the CBF file starts with a list of names that the server never fills
+        let missing_count = reader.read_u16_le().await?;
+        for _ in 0..missing_count {
+            read_name(&mut reader).await?;
+        }
+
+        let envelope = Self {
+            reader,
+            names: HashSet::new(),
+        };
+        Ok((response, envelope))
+    }
+
+    /// The name and size of the next file, whose data has to be read from `data` before
+    /// asking for the one after it
+    pub async fn next_entry(&mut self) -> io::Result<Option<(String, u64)>> {
+        // the envelope ends where an entry would start
+        let mut size = [0; 8];
+        if self.reader.read(&mut size[..1]).await? == 0 {
+            return Ok(None);
+        }
+        self.reader.read_exact(&mut size[1..]).await?;
+
+        let name = read_name(&mut self.reader).await?;
+        if !self.names.insert(name.clone()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Duplicate entry {:?}", name),
+            ));
+        }
+
+        Ok(Some((name, u64::from_le_bytes(size))))
+    }
+
+    pub fn data(&mut self, size: u64) -> Take<&mut R> {
+        (&mut self.reader).take(size)
+    }
+}
+
+async fn read_name<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<String> {
+    let mut name = vec![0; reader.read_u16_le().await? as usize];
+    reader.read_exact(&mut name).await?;
+    let name = String::from_utf8(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8"))?;
+    Ok(utils::normalize_name(&name))
+}
+
+/// Where the file the server calls `name` is written: over the file that's already
+/// there, which may have an NFD name, otherwise at its local name
+pub(crate) fn local_path(
+    config: &Config,
+    names: &NameMap,
+    disk_names: &DiskNames,
+    name: &str,
+) -> Option<PathBuf> {
+    match disk_names.get(name) {
+        Some(disk_name) => Some(Path::new(&config.music_dir).join(disk_name)),
+        None => file_names::path(&config.music_dir, names.local(name)),
+    }
+}
+
+/// A file being written next to its final path. It only takes the place of the file
+/// once it's complete and is removed again when dropped before, for example when the
+/// sync is cancelled.
+struct PartialFile {
+    temp: PathBuf,
+    done: bool,
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = std::fs::remove_file(&self.temp);
+        }
+    }
+}
+
+/// Writes everything `data` holds to `path`, exactly `size` bytes are expected
+pub async fn write_file<D>(path: &Path, size: u64, mut data: D) -> io::Result<()>
+where
+    D: AsyncRead + Unpin,
+{
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let mut partial = PartialFile {
+        temp: path.with_file_name(format!(".{}.part", file_name)),
+        done: false,
+    };
+
+    let mut file = tokio::fs::File::create(&partial.temp).await?;
+    let written = tokio::io::copy(&mut data, &mut file).await?;
+    if written != size {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    file.flush().await?;
+    drop(file);
+
+    tokio::fs::rename(&partial.temp, path).await?;
+    partial.done = true;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use utils::protocol::{ServerInfo, SyncStatus, PROTOCOL_VERSION};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_envelope() {
+        let mut response =
+            SyncResponse::new(PROTOCOL_VERSION, SyncStatus::Extra, ServerInfo::new("test"));
+        response.missing.insert("up.mp3".to_string());
+        let entries = HashMap::from([("a.mp3", vec![1; 100]), ("b.m3u", b"a.mp3".to_vec())]);
+
+        let mut buffer = Vec::new();
+        response.write_with(&mut buffer, &entries).unwrap();
+
+        let (header, mut envelope) = Envelope::open(buffer.as_slice()).await.unwrap();
+        assert_eq!(header.missing, response.missing);
+
+        let mut read = HashMap::new();
+        while let Some((name, size)) = envelope.next_entry().await.unwrap() {
+            let mut data = Vec::new();
+            envelope.data(size).read_to_end(&mut data).await.unwrap();
+            read.insert(name, data);
+        }
+        assert_eq!(read.len(), 2);
+        assert_eq!(read["b.m3u"], b"a.mp3");
+
+        // a truncated file is an error, not the end of the envelope
+        buffer.truncate(buffer.len() - 1);
+        let (_, mut envelope) = Envelope::open(buffer.as_slice()).await.unwrap();
+        let mut result = Ok(());
+        while let Some((_, size)) = envelope.next_entry().await.unwrap() {
+            let mut data = Vec::new();
+            envelope.data(size).read_to_end(&mut data).await.unwrap();
+            if data.len() as u64 != size {
+                result = Err(());
+            }
+        }
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_write_file() {
+        let dir = std::env::temp_dir().join(format!("music_sync_partial_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.mp3");
+
+        write_file(&path, 3, &b"abc"[..]).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"abc");
+
+        // an incomplete file neither replaces the old one nor stays around
+        assert!(write_file(&path, 4, &b"xyz"[..]).await.is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"abc");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,65 @@
+use std::{fmt, io};
+
+use reqwest::StatusCode;
+use utils::protocol::{ServerInfo, PROTOCOL_VERSION};
+
+/// Why a sync stopped. Failed uploads aren't errors, they are counted in the summary and
+/// retried on the next sync.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The server couldn't be reached, even after retrying
+    Http(reqwest::Error),
+    /// The server refused the sync
+    Server {
+        status: StatusCode,
+        message: String,
+    },
+    InvalidResponse(io::Error),
+    /// The server and the client don't share a protocol version
+    Incompatible(ServerInfo),
+    Config(String),
+    Cancelled,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{}", err),
+            Self::Http(err) => write!(f, "Failed to sync files: {}", err),
+            Self::Server { status, message } => {
+                write!(f, "Failed to sync files: {} {}", status, message)
+            }
+            Self::InvalidResponse(err) => write!(f, "Invalid response from the server: {}", err),
+            Self::Incompatible(server) => write!(
+                f,
+                "The server (version {}) supports protocols {} to {} but this client speaks {}, update the older one",
+                server.version, server.min_protocol, server.max_protocol, PROTOCOL_VERSION
+            ),
+            Self::Config(message) => write!(f, "{}", message),
+            Self::Cancelled => write!(f, "Cancelled, the files synced so far are kept"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) | Self::InvalidResponse(err) => Some(err),
+            Self::Http(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Self::Http(err)
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use utils::protocol::UploadResult;
+
+/// Progress is reported at most this often for each direction
+const INTERVAL: Duration = Duration::from_millis(500);
+
+/// Everything a sync reports while it runs. Serialized, every event is a JSON object
+/// with an `event` field telling them apart.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Message {
+        level: Level,
+        message: String,
+    },
+    Progress(ProgressUpdate),
+    /// A file was downloaded, or the server answered for an uploaded one
+    File {
+        direction: Direction,
+        name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bytes: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        result: Option<UploadResult>,
+    },
+    Summary(Summary),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Info,
+    Warning,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Download,
+    Upload,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProgressUpdate {
+    pub direction: Direction,
+    pub bytes: u64,
+    pub total_bytes: Option<u64>,
+    pub files: u64,
+    pub total_files: Option<u64>,
+    /// Bytes per second since the transfer started
+    pub rate: f64,
+    /// Seconds left, when the total is known
+    pub eta: Option<f64>,
+}
+
+/// What was transferred in one direction
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Transfer {
+    pub files: u64,
+    pub bytes: u64,
+    pub seconds: f64,
+}
+
+/// The outcome of a whole sync
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Summary {
+    pub downloaded: Option<Transfer>,
+    pub uploaded: Option<Transfer>,
+    /// These are retried on the next sync
+    pub failed_uploads: usize,
+}
+
+/// Where a session sends its events, cheap to clone into the tasks of a sync
+#[derive(Clone)]
+pub struct Events(Arc<dyn Fn(&Event) + Send + Sync>);
+
+impl Events {
+    pub fn new<F: Fn(&Event) + Send + Sync + 'static>(handler: F) -> Self {
+        Self(Arc::new(handler))
+    }
+
+    pub fn emit(&self, event: Event) {
+        (self.0)(&event);
+    }
+
+    pub fn info(&self, message: impl Into<String>) {
+        self.emit(Event::Message {
+            level: Level::Info,
+            message: message.into(),
+        });
+    }
+
+    pub fn warn(&self, message: impl Into<String>) {
+        self.emit(Event::Message {
+            level: Level::Warning,
+            message: message.into(),
+        });
+    }
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self::new(|_| {})
+    }
+}
+
+impl fmt::Debug for Events {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Events")
+    }
+}
+
+/// Counts the bytes and files of a transfer, shared with the streams feeding it
+pub struct Progress {
+    direction: Direction,
+    total_bytes: Option<u64>,
+    total_files: Option<u64>,
+    bytes: AtomicU64,
+    files: AtomicU64,
+    start: Instant,
+    last_report: Mutex<Instant>,
+    events: Events,
+}
+
+impl Progress {
+    pub fn new(
+        direction: Direction,
+        total_bytes: Option<u64>,
+        total_files: Option<u64>,
+        events: Events,
+    ) -> Self {
+        let start = Instant::now();
+        Self {
+            direction,
+            total_bytes,
+            total_files,
+            bytes: AtomicU64::new(0),
+            files: AtomicU64::new(0),
+            start,
+            last_report: Mutex::new(start),
+            events,
+        }
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn add_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.report_if_due();
+    }
+
+    /// Takes back bytes that have to be sent again
+    pub fn remove_bytes(&self, bytes: u64) {
+        self.bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_files(&self, files: u64) {
+        self.files.fetch_add(files, Ordering::Relaxed);
+        self.report_if_due();
+    }
+
+    fn report_if_due(&self) {
+        let mut last_report = self.last_report.lock().unwrap();
+        if last_report.elapsed() >= INTERVAL {
+            *last_report = Instant::now();
+            self.events.emit(Event::Progress(self.update()));
+        }
+    }
+
+    pub fn update(&self) -> ProgressUpdate {
+        let bytes = self.bytes();
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            bytes as f64 / elapsed
+        } else {
+            0.0
+        };
+        let eta = self
+            .total_bytes
+            .filter(|_| rate > 0.0)
+            .map(|total| total.saturating_sub(bytes) as f64 / rate);
+
+        ProgressUpdate {
+            direction: self.direction,
+            bytes,
+            total_bytes: self.total_bytes,
+            files: self.files.load(Ordering::Relaxed),
+            total_files: self.total_files,
+            rate,
+            eta,
+        }
+    }
+
+    /// The final numbers, they are reported in the summary
+    pub fn finish(&self) -> Transfer {
+        let update = self.update();
+        Transfer {
+            files: update.files,
+            bytes: update.bytes,
+            seconds: self.start.elapsed().as_secs_f64(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress() {
+        let progress = Progress::new(Direction::Upload, Some(10), Some(2), Events::default());
+        progress.add_bytes(10);
+        progress.remove_bytes(6);
+        progress.add_files(1);
+
+        let transfer = progress.finish();
+        assert_eq!((transfer.files, transfer.bytes), (1, 4));
+
+        let update = Event::Progress(progress.update());
+        let json = serde_json::to_value(update).unwrap();
+        assert_eq!(json["event"], "progress");
+        assert_eq!(json["direction"], "upload");
+        assert_eq!(json["bytes"], 4);
+    }
+}
@@ -0,0 +1,33 @@
+//! The sync engine of the client: scanning the music directory, planning what fits,
+//! talking to the server and writing the files, with events for progress and results.
+//!
+//! ```no_run
+//! # async fn sync(config: sync_core::Config) -> Result<(), sync_core::Error> {
+//! use sync_core::{events::Events, SyncSession};
+//!
+//! let events = Events::new(|event| println!("{}", serde_json::to_string(event).unwrap()));
+//! let mut session = SyncSession::new(config, "state.json", events)?;
+//! let plan = session.plan().await?;
+//! println!("The server is missing {} files", plan.uploads.len());
+//! let summary = session.apply(plan).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod config;
+mod download;
+mod error;
+pub mod events;
+mod names;
+pub mod planner;
+pub mod retry;
+mod session;
+pub mod snapshot;
+mod state;
+pub mod trash;
+mod upload;
+
+pub use config::Config;
+pub use error::Error;
+pub use session::{SyncPlan, SyncSession};
+pub use tokio_util::sync::CancellationToken;
@@ -0,0 +1,161 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsStr,
+};
+
+use serde::{Deserialize, Serialize};
+use utils::file_names;
+
+/// FAT32 and exFAT limit names to 255 UTF-16 code units
+const MAX_NAME_LENGTH: usize = 255;
+
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Maps the names used by the server (canonical) to the names of the files on this device.
+/// Only the names that had to change are stored.
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct NameMap {
+    local: BTreeMap<String, String>,
+}
+
+impl NameMap {
+    pub fn local<'a>(&'a self, canonical: &'a str) -> &'a str {
+        self.local.get(canonical).map_or(canonical, String::as_str)
+    }
+
+    /// Local name to canonical name for every renamed file
+    pub fn reverse(&self) -> HashMap<&str, &str> {
+        self.local
+            .iter()
+            .map(|(canonical, local)| (local.as_str(), canonical.as_str()))
+            .collect()
+    }
+
+    pub fn remove(&mut self, canonical: &str) {
+        self.local.remove(canonical);
+    }
+
+    /// Returns the local name for a file that is about to be written, picking a new
+    /// filesystem safe one if needed. `taken` holds the lowercase local names in use
+    /// since FAT is case insensitive, the new name is added to it.
+    pub fn assign(&mut self, canonical: &str, taken: &mut HashSet<String>) -> String {
+        if let Some(local) = self.local.get(canonical) {
+            return local.clone();
+        }
+
+        let safe = sanitize(canonical);
+        let (stem, extension) = match safe.rsplit_once('.') {
+            Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+            _ => (safe.as_str(), String::new()),
+        };
+
+        let mut local = safe.clone();
+        let mut counter = 2;
+        while taken.contains(&local.to_lowercase()) {
+            local = format!("{} ({}){}", stem, counter, extension);
+            counter += 1;
+        }
+
+        taken.insert(local.to_lowercase());
+        if local != canonical {
+            self.local.insert(canonical.to_string(), local.clone());
+        }
+        local
+    }
+}
+
+/// Replaces the characters FAT doesn't allow, trailing dots and spaces and reserved
+/// device names, and shortens names that are too long while keeping the extension.
+/// Both `name` and the result are escaped with `file_names::encode`.
+pub fn sanitize(name: &str) -> String {
+    // bytes that aren't valid UTF-8 can't be written to FAT either
+    let name = file_names::decode(name)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| name.to_string());
+
+    let mut safe: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' | '\u{fffd}' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    let trimmed_length = safe.trim_end_matches(['.', ' ']).len();
+    if trimmed_length < safe.len() {
+        safe.truncate(trimmed_length);
+        safe.push('_');
+    }
+
+    let stem = safe.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        safe.insert(stem.len(), '_');
+    }
+
+    if safe.encode_utf16().count() > MAX_NAME_LENGTH {
+        let extension = safe
+            .rsplit_once('.')
+            .map(|(_, extension)| format!(".{}", extension))
+            .filter(|extension| extension.len() <= 16)
+            .unwrap_or_default();
+
+        let mut stem = String::new();
+        let budget = MAX_NAME_LENGTH - extension.encode_utf16().count();
+        for c in safe.chars() {
+            if stem.encode_utf16().count() + c.len_utf16() > budget {
+                break;
+            }
+            stem.push(c);
+        }
+        safe = stem + &extension;
+    }
+
+    file_names::encode(OsStr::new(&safe))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize("AC/DC: Live?.mp3"), "AC_DC_ Live_.mp3");
+        assert_eq!(sanitize("Untitled..."), "Untitled_");
+        assert_eq!(sanitize("con.mp3"), "con_.mp3");
+        assert_eq!(sanitize("fine.flac"), "fine.flac");
+        assert_eq!(sanitize("caf%E9.mp3"), "caf_.mp3");
+        assert_eq!(sanitize("%2541.mp3"), "%2541.mp3");
+
+        let long = format!("{}.flac", "a".repeat(300));
+        let safe = sanitize(&long);
+        assert_eq!(safe.len(), MAX_NAME_LENGTH);
+        assert!(safe.ends_with(".flac"));
+    }
+
+    #[test]
+    fn test_assign_case_collision() {
+        let mut names = NameMap::default();
+        let mut taken = HashSet::new();
+
+        assert_eq!(names.assign("Song.mp3", &mut taken), "Song.mp3");
+        assert_eq!(names.assign("song.mp3", &mut taken), "song (2).mp3");
+        assert_eq!(names.assign("What?.mp3", &mut taken), "What_.mp3");
+
+        // assignments are stable across runs
+        assert_eq!(
+            names.assign("song.mp3", &mut HashSet::new()),
+            "song (2).mp3"
+        );
+
+        assert_eq!(names.local("Song.mp3"), "Song.mp3");
+        assert_eq!(names.reverse().get("What_.mp3"), Some(&"What?.mp3"));
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::{BTreeSet, HashSet};
+
+use serde::Deserialize;
+use utils::{cbf, filter::SyncFilter, tags::TrackInfo};
+
+/// A track as listed by the server's `/library` endpoint
+#[derive(Deserialize)]
+pub struct LibraryTrack {
+    pub name: String,
+    pub size: u64,
+    pub modified: u64,
+    #[serde(flatten)]
+    pub info: TrackInfo,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Priority {
+    /// Most recently added first
+    Recent,
+    /// Tracks in the favorites playlist first, then the most recent ones
+    Favorites,
+    /// A random selection that changes every `rotation_days`
+    Random,
+}
+
+impl Priority {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "recent" => Some(Self::Recent),
+            "favorites" => Some(Self::Favorites),
+            "random" => Some(Self::Random),
+            _ => None,
+        }
+    }
+}
+
+pub struct Budget {
+    pub max_size: u64,
+    pub priority: Priority,
+    pub favorites: HashSet<String>,
+    pub rotation_days: u64,
+}
+
+pub struct Plan {
+    /// Server files that don't fit. They are listed as skipped in the manifest so the
+    /// server doesn't send them.
+    pub skipped: HashSet<String>,
+    /// Previously downloaded files that no longer fit and have to be deleted
+    pub evict: Vec<String>,
+}
+
+/// Picks the server files to keep on the device within `budget.max_size`.
+/// Files the user added locally always stay and count against the budget first.
+pub fn plan(
+    budget: &Budget,
+    filter: &SyncFilter,
+    library: Vec<LibraryTrack>,
+    local_files: &cbf::FileEntries,
+    downloaded: &BTreeSet<String>,
+    today: u64,
+) -> Plan {
+    let library_names: HashSet<String> = library.iter().map(|track| track.name.clone()).collect();
+
+    let is_evictable = |name: &str| downloaded.contains(name) && library_names.contains(name);
+
+    let mut used: u64 = local_files
+        .iter()
+        .filter(|(name, _)| !is_evictable(name))
+        .map(|(_, data)| data.len() as u64)
+        .sum();
+
+    let mut candidates: Vec<LibraryTrack> = library
+        .into_iter()
+        .filter(|track| filter.matches(&track.name, Some(&track.info)))
+        .filter(|track| !local_files.contains_key(&track.name) || is_evictable(&track.name))
+        .collect();
+
+    let rotation = today / budget.rotation_days.max(1);
+    candidates.sort_by(|a, b| {
+        let order = match budget.priority {
+            Priority::Recent => b.modified.cmp(&a.modified),
+            Priority::Favorites => budget
+                .favorites
+                .contains(&b.name)
+                .cmp(&budget.favorites.contains(&a.name))
+                .then(b.modified.cmp(&a.modified)),
+            Priority::Random => random_key(&a.name, rotation).cmp(&random_key(&b.name, rotation)),
+        };
+        order.then_with(|| a.name.cmp(&b.name))
+    });
+
+    let mut skipped = HashSet::new();
+    let mut evict = Vec::new();
+
+    for track in candidates {
+        if used + track.size <= budget.max_size {
+            used += track.size;
+        } else {
+            if local_files.contains_key(&track.name) {
+                evict.push(track.name.clone());
+            }
+            skipped.insert(track.name);
+        }
+    }
+
+    Plan { skipped, evict }
+}
+
+/// FNV-1a of the name and rotation. Unlike `DefaultHasher` it's the same with every Rust
+/// release, so a new build of the client doesn't pick other files before the rotation
+fn random_key(name: &str, rotation: u64) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for byte in name.bytes().chain(rotation.to_le_bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(name: &str, size: u64, modified: u64) -> LibraryTrack {
+        LibraryTrack {
+            name: name.to_string(),
+            size,
+            modified,
+            info: TrackInfo::default(),
+        }
+    }
+
+    fn budget(max_size: u64, priority: Priority) -> Budget {
+        Budget {
+            max_size,
+            priority,
+            favorites: HashSet::from(["old.mp3".to_string()]),
+            rotation_days: 7,
+        }
+    }
+
+    #[test]
+    fn test_plan_recent() {
+        let library = vec![
+            track("old.mp3", 40, 1),
+            track("new.mp3", 40, 3),
+            track("mid.mp3", 40, 2),
+        ];
+        let local_files = cbf::FileEntries::from([
+            ("mine.mp3".to_string(), vec![0; 20]),
+            ("old.mp3".to_string(), vec![0; 40]),
+        ]);
+        let downloaded = BTreeSet::from(["old.mp3".to_string()]);
+
+        let plan = plan(
+            &budget(100, Priority::Recent),
+            &SyncFilter::default(),
+            library,
+            &local_files,
+            &downloaded,
+            0,
+        );
+
+        // mine.mp3 is pinned, so only the two newest fit
+        assert_eq!(plan.evict, vec!["old.mp3".to_string()]);
+        assert_eq!(plan.skipped, HashSet::from(["old.mp3".to_string()]));
+    }
+
+    #[test]
+    fn test_plan_favorites() {
+        let library = vec![track("old.mp3", 40, 1), track("new.mp3", 40, 3)];
+
+        let plan = plan(
+            &budget(50, Priority::Favorites),
+            &SyncFilter::default(),
+            library,
+            &cbf::FileEntries::new(),
+            &BTreeSet::new(),
+            0,
+        );
+
+        assert!(plan.evict.is_empty());
+        assert_eq!(plan.skipped, HashSet::from(["new.mp3".to_string()]));
+    }
+
+    #[test]
+    fn test_plan_random() {
+        let names = ["a.mp3", "b.mp3", "c.mp3"];
+        let kept = |today| {
+            let library = names.iter().map(|name| track(name, 40, 1)).collect();
+            let plan = plan(
+                &budget(50, Priority::Random),
+                &SyncFilter::default(),
+                library,
+                &cbf::FileEntries::new(),
+                &BTreeSet::new(),
+                today,
+            );
+            names
+                .into_iter()
+                .find(|name| !plan.skipped.contains(*name))
+                .unwrap()
+        };
+
+        // the same pick on every build, until the rotation changes it
+        assert_eq!(kept(0), "a.mp3");
+        assert_eq!(kept(6), "a.mp3");
+        assert_eq!(kept(7), "c.mp3");
+    }
+}
@@ -0,0 +1,118 @@
+use std::time::Duration;
+
+use rand::Rng;
+use reqwest::{header::RETRY_AFTER, RequestBuilder, Response, StatusCode};
+
+use crate::events::Events;
+
+/// The longest the client waits between two attempts, unless the server asks for more
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// How often and how patiently requests to the server are retried
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts after the first one, 0 disables retrying
+    pub retries: u32,
+    /// The wait before the first retry, it doubles after each one
+    pub initial_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 4,
+            initial_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The wait before retry number `retry` (starting at 0), between half and one and a
+    /// half times the exponential backoff so that clients that failed together don't
+    /// all come back at the same moment. `Retry-After` is a minimum.
+    pub fn delay(&self, retry: u32, retry_after: Option<Duration>) -> Duration {
+        let backoff = self
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(MAX_DELAY);
+        let jittered = backoff.mul_f64(rand::thread_rng().gen_range(0.5..1.5));
+
+        jittered.max(retry_after.unwrap_or_default())
+    }
+
+    /// Sends the request built by `request` until the server answers with something
+    /// retrying won't change. Connection errors, timeouts, 429 and 5xx are retried, the
+    /// last result is returned. The request is built again for every attempt since
+    /// streamed bodies can only be sent once.
+    pub async fn send<F>(&self, mut request: F, events: &Events) -> reqwest::Result<Response>
+    where
+        F: FnMut() -> RequestBuilder,
+    {
+        let mut retry = 0;
+
+        loop {
+            let result = request().send().await;
+
+            let retry_after = match &result {
+                Ok(response) if !is_retryable(response.status()) => return result,
+                Ok(response) => response
+                    .headers()
+                    .get(RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok()?.parse().ok())
+                    .map(Duration::from_secs),
+                Err(err) if !(err.is_connect() || err.is_timeout() || err.is_request()) => {
+                    return result
+                }
+                Err(_) => None,
+            };
+
+            if retry >= self.retries {
+                return result;
+            }
+
+            let delay = self.delay(retry, retry_after);
+            let reason = match &result {
+                Ok(response) => format!("The server answered {}", response.status()),
+                Err(err) => err.to_string(),
+            };
+            events.warn(format!(
+                "{}, retrying in {:.1} seconds",
+                reason,
+                delay.as_secs_f64()
+            ));
+            tokio::time::sleep(delay).await;
+            retry += 1;
+        }
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy::default();
+
+        for retry in 0..4 {
+            let backoff = Duration::from_secs(1 << retry);
+            let delay = policy.delay(retry, None);
+            assert!(delay >= backoff / 2 && delay <= backoff * 3 / 2);
+        }
+
+        assert!(policy.delay(20, None) <= MAX_DELAY * 3 / 2);
+        assert!(policy.delay(0, Some(Duration::from_secs(30))) >= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        assert!(is_retryable(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(is_retryable(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_retryable(StatusCode::UNAUTHORIZED));
+        assert!(!is_retryable(StatusCode::OK));
+    }
+}
@@ -0,0 +1,746 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    future::Future,
+    io,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::{io::StreamReader, sync::CancellationToken};
+use utils::{
+    bandwidth::Limiter,
+    cbf,
+    encryption::TokenVerifier,
+    playlist,
+    protocol::{self, ErrorResponse, Manifest, ServerInfo, SyncStatus, UploadResult},
+    DiskNames,
+};
+
+use crate::{
+    download::{self, Envelope},
+    events::{Direction, Event, Events, Progress, Summary, Transfer},
+    names::NameMap,
+    planner::{self, Budget, LibraryTrack, Priority},
+    snapshot,
+    state::State,
+    upload, Config, Error,
+};
+
+/// The body of the sync response, read as it arrives
+type ResponseBody = StreamReader<Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>, Bytes>;
+
+/// What a sync is about to do. The server has answered with what it's missing, the
+/// files for this client are still waiting in the response until the plan is applied.
+pub struct SyncPlan {
+    pub status: SyncStatus,
+    pub server: ServerInfo,
+    /// Files the server is missing, with the uploads that failed on an earlier sync
+    pub uploads: BTreeSet<String>,
+    file_entries: cbf::FileEntries,
+    /// The names the local files have on disk, by server name
+    disk_names: DiskNames,
+    taken_names: HashSet<String>,
+    envelope: Envelope<ResponseBody>,
+    progress: Arc<Progress>,
+}
+
+impl SyncPlan {
+    pub fn is_synced(&self) -> bool {
+        self.uploads.is_empty() && self.status == SyncStatus::Synced
+    }
+}
+
+/// Syncs a music directory with a server. The state is loaded from `state_path` and
+/// saved there after every sync, even a cancelled one.
+pub struct SyncSession {
+    config: Config,
+    state: State,
+    state_path: PathBuf,
+    client: reqwest::Client,
+    encrypted_token: String,
+    events: Events,
+    cancel: CancellationToken,
+}
+
+impl SyncSession {
+    pub fn new(
+        config: Config,
+        state_path: impl Into<PathBuf>,
+        events: Events,
+    ) -> Result<Self, Error> {
+        let state_path = state_path.into();
+        let state = State::load(&state_path)?;
+        let encrypted_token = TokenVerifier::new(&config.token).encrypt(config.token.as_bytes());
+
+        Ok(Self {
+            config,
+            state,
+            state_path,
+            client: reqwest::Client::new(),
+            encrypted_token,
+            events,
+            cancel: CancellationToken::new(),
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Makes the session stop when `cancel` is cancelled, to stop several at once
+    pub fn with_cancel_handle(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Cancelling stops the sync in progress, partial files remove themselves and the
+    /// files that were complete are kept
+    pub fn cancel_handle(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Plans the sync and applies it right away
+    pub async fn run(&mut self) -> Result<Summary, Error> {
+        let plan = self.plan().await?;
+        self.apply(plan).await
+    }
+
+    /// Scans the music directory, evicts what no longer fits in `max_size` and asks the
+    /// server what each side is missing
+    pub async fn plan(&mut self) -> Result<SyncPlan, Error> {
+        let cancel = self.cancel.clone();
+        let result = cancellable(&cancel, self.plan_inner()).await;
+        self.save_state(result)
+    }
+
+    /// Uploads what the server is missing while the files for this client are downloaded.
+    /// Uploads that fail don't stop the sync, they are counted in the summary.
+    pub async fn apply(&mut self, plan: SyncPlan) -> Result<Summary, Error> {
+        let cancel = self.cancel.clone();
+        let result = cancellable(&cancel, self.apply_inner(plan)).await;
+        self.save_state(result)
+    }
+
+    /// Brings the music directory back to snapshot `id` of the server's library. Unlike a
+    /// sync, files the snapshot doesn't have are deleted, whether they came from the
+    /// server or not, and `max_size` and the filters don't apply.
+    pub async fn restore_snapshot(&mut self, id: u64) -> Result<Summary, Error> {
+        let cancel = self.cancel.clone();
+        let restore = snapshot::restore(
+            &self.client,
+            &self.encrypted_token,
+            &self.config,
+            &mut self.state,
+            &self.events,
+            id,
+        );
+        let result = cancellable(&cancel, restore).await;
+        self.save_state(result)
+    }
+
+    fn save_state<T>(&self, result: Result<T, Error>) -> Result<T, Error> {
+        let saved = self.state.save(&self.state_path);
+        let value = result?;
+        saved?;
+        Ok(value)
+    }
+
+    async fn plan_inner(&mut self) -> Result<SyncPlan, Error> {
+        let config = &self.config;
+        let music_dir = config.music_dir.clone();
+        let non_utf8_names = config.non_utf8_names;
+        let (disk_names, file_entries) =
+            tokio::task::spawn_blocking(move || utils::get_files(&music_dir, non_utf8_names))
+                .await
+                .map_err(io::Error::other)??;
+
+        // everything from here on uses the server's names, the local ones only matter when
+        // reading or writing files
+        let taken_names: HashSet<String> =
+            disk_names.keys().map(|name| name.to_lowercase()).collect();
+        let reverse_names = self.state.names.reverse();
+        let canonical = |name: String| match reverse_names.get(name.as_str()) {
+            Some(canonical) => canonical.to_string(),
+            None => name,
+        };
+        let mut file_entries: cbf::FileEntries = file_entries
+            .into_iter()
+            .map(|(name, data)| (canonical(name), data))
+            .collect();
+        let mut disk_names: DiskNames = disk_names
+            .into_iter()
+            .map(|(name, disk_name)| (canonical(name), disk_name))
+            .collect();
+        let mut file_names: HashSet<String> = file_entries.keys().cloned().collect();
+
+        // excluded files are left alone, they are neither uploaded nor reported to the server
+        if !config.filter.is_empty() {
+            file_entries.retain(|name, data| {
+                let info = (!config.filter.rules.is_empty()).then(|| utils::tags::parse(data));
+                config.filter.matches(name, info.as_ref())
+            });
+            file_names.retain(|name| file_entries.contains_key(name));
+        }
+
+        let mut manifest = Manifest {
+            filter: config.filter.clone(),
+            ..Default::default()
+        };
+        if let Some(max_size) = config.max_size {
+            manifest.skipped = self
+                .fit_to_budget(max_size, &mut file_entries, &mut disk_names)
+                .await?;
+            file_names.retain(|name| file_entries.contains_key(name));
+        }
+        manifest.files = file_names.into_iter().collect();
+
+        let config = &self.config;
+        let manifest = manifest.to_json();
+        let response = config
+            .retry
+            .send(
+                || {
+                    self.client
+                        .get(config.url("sync"))
+                        .header("Authorization", &self.encrypted_token)
+                        .header("Content-Type", protocol::JSON_CONTENT_TYPE)
+                        .body(manifest.clone())
+                },
+                &self.events,
+            )
+            .await?;
+
+        // errors come as JSON, everything else is an envelope that's read as it arrives
+        let status = response.status();
+        let is_envelope = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .is_some_and(|value| value == protocol::ENVELOPE_CONTENT_TYPE);
+        if !status.is_success() && !is_envelope {
+            let message = ErrorResponse::describe(&response.bytes().await?);
+            return Err(Error::Server { status, message });
+        }
+
+        let progress = Arc::new(Progress::new(
+            Direction::Download,
+            response.content_length(),
+            None,
+            self.events.clone(),
+        ));
+        let limiter = Arc::new(Limiter::new(config.download_limit.clone()));
+        let body = {
+            let progress = progress.clone();
+            response.bytes_stream().then(move |chunk| {
+                let (progress, limiter) = (progress.clone(), limiter.clone());
+                async move {
+                    let chunk = chunk.map_err(io::Error::other)?;
+                    let delay = limiter.delay(chunk.len() as u64);
+                    if !delay.is_zero() {
+                        tokio::time::sleep(delay).await;
+                    }
+                    progress.add_bytes(chunk.len() as u64);
+                    Ok::<_, io::Error>(chunk)
+                }
+            })
+        };
+        let body: ResponseBody = StreamReader::new(Box::pin(body));
+        let (response, envelope) = Envelope::open(body).await.map_err(Error::InvalidResponse)?;
+
+        if response.status == SyncStatus::Incompatible {
+            return Err(Error::Incompatible(response.server));
+        }
+
+        // uploads that failed on an earlier sync are sent again, those the server got in
+        // the meantime are acknowledged as identical
+        let mut uploads: BTreeSet<String> = response.missing.into_iter().collect();
+        let pending = self
+            .state
+            .pending_uploads()
+            .filter(|name| file_entries.contains_key(*name) && !uploads.contains(*name))
+            .cloned()
+            .collect::<Vec<_>>();
+        if !pending.is_empty() {
+            self.events.info(format!(
+                "Retrying {} uploads that failed before",
+                pending.len()
+            ));
+            uploads.extend(pending);
+        }
+
+        Ok(SyncPlan {
+            status: response.status,
+            server: response.server,
+            uploads,
+            file_entries,
+            disk_names,
+            taken_names,
+            envelope,
+            progress,
+        })
+    }
+
+    async fn apply_inner(&mut self, plan: SyncPlan) -> Result<Summary, Error> {
+        if plan.is_synced() {
+            self.events.info("Already Synced!");
+            return Ok(Summary::default());
+        }
+
+        let SyncPlan {
+            server,
+            uploads: missing_files,
+            file_entries,
+            disk_names,
+            mut taken_names,
+            envelope,
+            progress,
+            ..
+        } = plan;
+        let Self {
+            config,
+            state,
+            client,
+            encrypted_token,
+            events,
+            ..
+        } = self;
+
+        events.info(format!(
+            "The server is missing {} files",
+            missing_files.len()
+        ));
+
+        // the uploads run while the files for this client are still arriving
+        let names = state.names.clone();
+        let uploader = Uploader {
+            client,
+            config,
+            encrypted_token,
+            events,
+        };
+        let uploads = async {
+            if missing_files.is_empty() {
+                return None;
+            }
+            Some(
+                uploader
+                    .sync_missing_files(
+                        &file_entries,
+                        &names,
+                        &missing_files,
+                        server.max_upload_bytes,
+                    )
+                    .await,
+            )
+        };
+        let downloads = async {
+            let result = download_files(
+                config,
+                state,
+                events,
+                &disk_names,
+                &mut taken_names,
+                envelope,
+                &progress,
+            )
+            .await;
+            (result, progress.finish())
+        };
+        let (uploads, (downloads, downloaded)) = tokio::join!(uploads, downloads);
+
+        downloads.map_err(Error::InvalidResponse)?;
+        events.info(format!("The client was missing {} files", downloaded.files));
+
+        let mut summary = Summary {
+            downloaded: (downloaded.files > 0).then_some(downloaded),
+            ..Default::default()
+        };
+        if let Some((uploads, transfer)) = uploads {
+            summary.failed_uploads = uploads
+                .values()
+                .filter(|result| matches!(result, UploadResult::Failed { .. }))
+                .count();
+            summary.uploaded = Some(transfer);
+            // only the failed ones are kept to be retried, the list would grow with every
+            // file ever uploaded otherwise
+            state.uploads.extend(uploads);
+            state
+                .uploads
+                .retain(|_, result| matches!(result, UploadResult::Failed { .. }));
+        }
+
+        events.emit(Event::Summary(summary.clone()));
+        Ok(summary)
+    }
+
+    /// Deletes the downloaded files that no longer fit and returns the server files that
+    /// shouldn't be downloaded
+    async fn fit_to_budget(
+        &mut self,
+        max_size: u64,
+        file_entries: &mut cbf::FileEntries,
+        disk_names: &mut DiskNames,
+    ) -> Result<BTreeSet<String>, Error> {
+        let config = &self.config;
+        let library = self.fetch_library().await?;
+
+        let favorites = match &config.favorites {
+            Some(playlist) if config.priority == Priority::Favorites => {
+                let mut url = reqwest::Url::parse(&config.url("files"))
+                    .map_err(|err| Error::Config(format!("Invalid server url: {}", err)))?;
+                url.path_segments_mut()
+                    .map_err(|_| Error::Config("Invalid server url".to_string()))?
+                    .push(playlist);
+
+                let response = self
+                    .client
+                    .get(url)
+                    .header("Authorization", &self.encrypted_token)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                let format = playlist::PlaylistFormat::from_name(playlist).ok_or_else(|| {
+                    Error::Config("The favorites file is not a playlist".to_string())
+                })?;
+                playlist::entries(&playlist::decode(&response.bytes().await?), format)
+                    .into_iter()
+                    .collect()
+            }
+            _ => HashSet::new(),
+        };
+
+        let budget = Budget {
+            max_size,
+            priority: config.priority,
+            favorites,
+            rotation_days: config.rotation_days,
+        };
+        let today = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / (24 * 60 * 60);
+
+        let plan = planner::plan(
+            &budget,
+            &config.filter,
+            library,
+            file_entries,
+            &self.state.downloaded,
+            today,
+        );
+
+        let state = &mut self.state;
+        for name in &plan.evict {
+            // a file the user already deleted is as good as evicted
+            if let Some(path) = download::local_path(config, &state.names, disk_names, name) {
+                match tokio::fs::remove_file(path).await {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                    _ => {}
+                }
+            }
+            file_entries.remove(name);
+            disk_names.remove(name);
+            state.downloaded.remove(name);
+            state.names.remove(name);
+        }
+        state.save(&self.state_path)?;
+
+        self.events.info(format!(
+            "Evicted {} files and skipped {} files to fit in the size limit",
+            plan.evict.len(),
+            plan.skipped.len()
+        ));
+
+        Ok(plan.skipped.into_iter().collect())
+    }
+
+    async fn fetch_library(&self) -> Result<Vec<LibraryTrack>, Error> {
+        #[derive(serde::Deserialize)]
+        struct LibraryPage {
+            total: usize,
+            tracks: Vec<LibraryTrack>,
+        }
+
+        const PAGE_SIZE: usize = 1000;
+
+        let mut library = Vec::new();
+        loop {
+            let page: LibraryPage = serde_json::from_str(
+                &self
+                    .client
+                    .get(self.config.url(&format!(
+                        "library?offset={}&limit={}",
+                        library.len(),
+                        PAGE_SIZE
+                    )))
+                    .header("Authorization", &self.encrypted_token)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .text()
+                    .await?,
+            )
+            .map_err(|err| {
+                Error::InvalidResponse(io::Error::new(io::ErrorKind::InvalidData, err))
+            })?;
+
+            let done = page.tracks.is_empty() || library.len() + page.tracks.len() >= page.total;
+            library.extend(page.tracks);
+            if done {
+                return Ok(library);
+            }
+        }
+    }
+}
+
+/// Runs `future` until it's done or the sync is cancelled
+async fn cancellable<T>(
+    cancel: &CancellationToken,
+    future: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    tokio::select! {
+        result = future => result,
+        _ = cancel.cancelled() => Err(Error::Cancelled),
+    }
+}
+
+/// Writes the files of the envelope as they arrive. Playlists are kept for the end,
+/// their paths can only be rewritten once every file has its local name.
+async fn download_files<R>(
+    config: &Config,
+    state: &mut State,
+    events: &Events,
+    disk_names: &DiskNames,
+    taken_names: &mut HashSet<String>,
+    mut envelope: Envelope<R>,
+    progress: &Progress,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut playlists = Vec::new();
+
+    while let Some((name, size)) = envelope.next_entry().await? {
+        if playlist::is_playlist(&name) {
+            let mut data = Vec::new();
+            envelope.data(size).read_to_end(&mut data).await?;
+            playlists.push((name, data));
+            continue;
+        }
+
+        // names are assigned once and kept in the state, so that case only collisions
+        // are resolved the same way on every run
+        if config.sanitize_names {
+            state.names.assign(&name, taken_names);
+        }
+
+        match download::local_path(config, &state.names, disk_names, &name) {
+            Some(path) => download::write_file(&path, size, envelope.data(size)).await?,
+            None => {
+                events.warn(format!("Skipping {:?}, the name can't be used here", name));
+                tokio::io::copy(&mut envelope.data(size), &mut tokio::io::sink()).await?;
+                continue;
+            }
+        }
+
+        progress.add_files(1);
+        events.emit(downloaded(&name, size));
+        state.downloaded.insert(name);
+    }
+
+    for (name, data) in playlists {
+        if config.sanitize_names {
+            state.names.assign(&name, taken_names);
+        }
+
+        let names = &state.names;
+        let data = playlist::rewrite(&name, &data, |path| {
+            playlist::to_local_path(names.local(path), &config.playlist_style)
+        })
+        .unwrap_or(data);
+
+        match download::local_path(config, names, disk_names, &name) {
+            Some(path) => download::write_file(&path, data.len() as u64, data.as_slice()).await?,
+            None => {
+                events.warn(format!("Skipping {:?}, the name can't be used here", name));
+                continue;
+            }
+        }
+
+        progress.add_files(1);
+        events.emit(downloaded(&name, data.len() as u64));
+        state.downloaded.insert(name);
+    }
+
+    Ok(())
+}
+
+fn downloaded(name: &str, bytes: u64) -> Event {
+    Event::File {
+        direction: Direction::Download,
+        name: name.to_string(),
+        bytes: Some(bytes),
+        result: None,
+    }
+}
+
+/// The parts of a session the uploads need, borrowed next to the downloads' state
+struct Uploader<'a> {
+    client: &'a reqwest::Client,
+    config: &'a Config,
+    encrypted_token: &'a str,
+    events: &'a Events,
+}
+
+impl Uploader<'_> {
+    async fn sync_missing_files(
+        &self,
+        file_entries: &cbf::FileEntries,
+        names: &NameMap,
+        missing_files: &BTreeSet<String>,
+        max_upload_bytes: Option<u64>,
+    ) -> (BTreeMap<String, UploadResult>, Transfer) {
+        // playlists are uploaded with library relative paths, the other clients rewrite
+        // them again
+        let playlist_root = match &self.config.playlist_style.root {
+            Some(root) => root,
+            None => &self.config.music_dir,
+        };
+        let reverse_names = names.reverse();
+        let missing_files = missing_files
+            .iter()
+            .map(|name| {
+                let data = file_entries.get(name).unwrap();
+                let playlist = playlist::rewrite(name, data, |path| {
+                    let path = playlist::to_library_path(path, Some(playlist_root));
+                    match reverse_names.get(path.as_str()) {
+                        Some(canonical) => canonical.to_string(),
+                        None => path,
+                    }
+                });
+                let data = match playlist {
+                    Some(playlist) => Cow::Owned(playlist),
+                    None => Cow::Borrowed(data),
+                };
+                (name, data)
+            })
+            .collect::<HashMap<_, _>>();
+
+        // the server refuses uploads above its limit, so they're split into batches under it
+        let (batches, too_large) = upload::batches(&missing_files, max_upload_bytes);
+
+        let mut uploads = BTreeMap::new();
+        for name in too_large {
+            let reason = "larger than the server's upload limit".to_string();
+            uploads.insert(name.clone(), UploadResult::Rejected { reason });
+        }
+
+        let total_bytes = batches
+            .iter()
+            .map(|batch| upload::encoded_size(&missing_files, batch))
+            .sum();
+        let total_files = batches.iter().map(Vec::len).sum::<usize>() as u64;
+        let progress = Arc::new(Progress::new(
+            Direction::Upload,
+            Some(total_bytes),
+            Some(total_files),
+            self.events.clone(),
+        ));
+        let limiter = Arc::new(Limiter::new(self.config.upload_limit.clone()));
+
+        // batches are sent a few at a time, each one builds its body when it starts
+        let mut batch_uploads = futures::stream::iter(batches)
+            .map(|batch| {
+                let batch_files = batch
+                    .iter()
+                    .map(|name| (*name, &missing_files[*name]))
+                    .collect::<HashMap<_, _>>();
+                let upload = self.upload_batch(batch_files, &progress, &limiter);
+                async move { (batch, upload.await) }
+            })
+            .buffer_unordered(self.config.concurrent_uploads);
+        while let Some((batch, result)) = batch_uploads.next().await {
+            match result {
+                Ok(results) => uploads.extend(results),
+                Err(error) => {
+                    self.events
+                        .warn(format!("Failed to sync missing files: {}", error));
+                    for name in batch {
+                        let error = error.clone();
+                        uploads.insert(name.clone(), UploadResult::Failed { error });
+                    }
+                }
+            }
+        }
+        drop(batch_uploads);
+
+        let transfer = progress.finish();
+
+        for (name, result) in &uploads {
+            self.events.emit(Event::File {
+                direction: Direction::Upload,
+                name: name.clone(),
+                bytes: None,
+                result: Some(result.clone()),
+            });
+        }
+        let written = uploads
+            .values()
+            .filter(|result| **result == UploadResult::Written)
+            .count();
+        self.events.info(format!(
+            "Uploaded {} of {} missing files",
+            written,
+            missing_files.len()
+        ));
+
+        (uploads, transfer)
+    }
+
+    async fn upload_batch<V: AsRef<Vec<u8>>>(
+        &self,
+        files: HashMap<&String, V>,
+        progress: &Arc<Progress>,
+        limiter: &Arc<Limiter>,
+    ) -> Result<BTreeMap<String, UploadResult>, String> {
+        let mut buffer = Vec::new();
+        cbf::write(&mut buffer, &files, None).expect("writing to a Vec can't fail");
+        drop(files);
+        let buffer: Arc<[u8]> = buffer.into();
+        let length = buffer.len();
+
+        // the body is counted as it's sent, and taken back when it has to be sent again
+        let sent = Arc::new(AtomicU64::new(0));
+        let request = || {
+            progress.remove_bytes(sent.swap(0, Ordering::Relaxed));
+            let body = upload::body(
+                buffer.clone(),
+                limiter.clone(),
+                progress.clone(),
+                sent.clone(),
+            );
+            self.client
+                .post(self.config.url("sync"))
+                .header("Authorization", self.encrypted_token)
+                .header(CONTENT_LENGTH, length)
+                .body(body)
+        };
+
+        let result = upload::send(request, &self.config.retry, self.events).await;
+        match &result {
+            Ok(results) => progress.add_files(results.len() as u64),
+            Err(_) => progress.remove_bytes(sent.swap(0, Ordering::Relaxed)),
+        }
+        result
+    }
+}
@@ -0,0 +1,183 @@
+//! The snapshots the server took of the library the config syncs, and bringing the music
+//! directory back to one of them
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+};
+
+use utils::{
+    encryption::TokenVerifier,
+    playlist,
+    protocol::{self, ErrorResponse, Snapshot, SnapshotInfo},
+    DiskNames,
+};
+
+use crate::{
+    download,
+    events::{Direction, Event, Events, Progress, Summary},
+    state::State,
+    Config, Error,
+};
+
+/// The snapshots of the library, oldest first
+pub async fn list(config: &Config, events: &Events) -> Result<Vec<SnapshotInfo>, Error> {
+    let client = reqwest::Client::new();
+    let token = TokenVerifier::new(&config.token).encrypt(config.token.as_bytes());
+    let response = get(&client, &token, config, config.url("snapshots"), events).await?;
+
+    serde_json::from_slice(&response).map_err(|err| Error::InvalidResponse(io::Error::other(err)))
+}
+
+async fn get(
+    client: &reqwest::Client,
+    token: &str,
+    config: &Config,
+    url: impl reqwest::IntoUrl + Clone,
+    events: &Events,
+) -> Result<bytes::Bytes, Error> {
+    let response = config
+        .retry
+        .send(
+            || client.get(url.clone()).header("Authorization", token),
+            events,
+        )
+        .await?;
+
+    let status = response.status();
+    let body = response.bytes().await?;
+    if !status.is_success() {
+        let message = ErrorResponse::describe(&body);
+        return Err(Error::Server { status, message });
+    }
+    Ok(body)
+}
+
+/// Makes the music directory hold exactly the files of snapshot `id`: files that
+/// differ or are missing are downloaded, the others are deleted. Playlists are always
+/// downloaded again, their local copies are rewritten so they can't be compared.
+pub(crate) async fn restore(
+    client: &reqwest::Client,
+    token: &str,
+    config: &Config,
+    state: &mut State,
+    events: &Events,
+    id: u64,
+) -> Result<Summary, Error> {
+    let url = config.url(&format!("snapshots/{}", id));
+    let snapshot: Snapshot =
+        serde_json::from_slice(&get(client, token, config, url, events).await?)
+            .map_err(|err| Error::InvalidResponse(io::Error::other(err)))?;
+
+    let music_dir = config.music_dir.clone();
+    let non_utf8_names = config.non_utf8_names;
+    let (disk_names, file_entries) =
+        tokio::task::spawn_blocking(move || utils::get_files(&music_dir, non_utf8_names))
+            .await
+            .map_err(io::Error::other)??;
+    let mut taken_names: HashSet<String> =
+        disk_names.keys().map(|name| name.to_lowercase()).collect();
+
+    // the local files by the server's names, like the snapshot
+    let reverse_names = state.names.reverse();
+    let canonical = |local: &str| {
+        reverse_names
+            .get(local)
+            .map_or(local.to_string(), |canonical| canonical.to_string())
+    };
+    let current = file_entries
+        .into_iter()
+        .map(|(local, data)| (canonical(&local), (local, data)))
+        .collect::<HashMap<_, _>>();
+    let mut disk_names = disk_names
+        .into_iter()
+        .map(|(local, disk_name)| (canonical(&local), disk_name))
+        .collect::<DiskNames>();
+
+    let mut removed = 0;
+    for (name, (local, _)) in &current {
+        if snapshot.files.contains_key(name) {
+            continue;
+        }
+        if let Some(path) = download::local_path(config, &state.names, &disk_names, name) {
+            tokio::fs::remove_file(path).await?;
+        }
+        disk_names.remove(name);
+        taken_names.remove(&local.to_lowercase());
+        state.downloaded.remove(name);
+        state.names.remove(name);
+        state.uploads.remove(name);
+        removed += 1;
+    }
+
+    let mut changed = snapshot
+        .files
+        .iter()
+        .filter(|(name, file)| {
+            playlist::is_playlist(name)
+                || current
+                    .get(*name)
+                    .is_none_or(|(_, data)| protocol::content_hash(data) != file.hash)
+        })
+        .collect::<Vec<_>>();
+    // playlists are rewritten once every file has its local name
+    changed.sort_by_key(|(name, _)| playlist::is_playlist(name));
+
+    let progress = Progress::new(
+        Direction::Download,
+        Some(changed.iter().map(|(_, file)| file.size).sum()),
+        Some(changed.len() as u64),
+        events.clone(),
+    );
+    for (name, file) in changed {
+        let mut url = reqwest::Url::parse(&config.url(&format!("snapshots/{}/files", id)))
+            .map_err(|err| Error::Config(format!("Invalid server url: {}", err)))?;
+        url.path_segments_mut()
+            .map_err(|_| Error::Config("Invalid server url".to_string()))?
+            .push(name);
+        let data = get(client, token, config, url, events).await?;
+        if protocol::content_hash(&data) != file.hash {
+            return Err(Error::InvalidResponse(io::Error::other(format!(
+                "{} doesn't match the snapshot",
+                name
+            ))));
+        }
+
+        if config.sanitize_names {
+            state.names.assign(name, &mut taken_names);
+        }
+        let names = &state.names;
+        let data = playlist::rewrite(name, &data, |path| {
+            playlist::to_local_path(names.local(path), &config.playlist_style)
+        })
+        .unwrap_or_else(|| data.to_vec());
+
+        let Some(path) = download::local_path(config, names, &disk_names, name) else {
+            events.warn(format!("Skipping {:?}, the name can't be used here", name));
+            continue;
+        };
+        download::write_file(&path, data.len() as u64, data.as_slice()).await?;
+
+        progress.add_bytes(file.size);
+        progress.add_files(1);
+        events.emit(Event::File {
+            direction: Direction::Download,
+            name: name.clone(),
+            bytes: Some(file.size),
+            result: None,
+        });
+        state.downloaded.insert(name.clone());
+    }
+
+    let downloaded = progress.finish();
+    events.info(format!(
+        "Restored snapshot {}: {} files downloaded, {} deleted",
+        id, downloaded.files, removed
+    ));
+    let summary = Summary {
+        downloaded: (downloaded.files > 0).then_some(downloaded),
+        ..Default::default()
+    };
+    events.emit(Event::Summary(summary.clone()));
+    Ok(summary)
+}
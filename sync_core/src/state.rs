@@ -0,0 +1,54 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use utils::protocol::UploadResult;
+
+use crate::names::NameMap;
+
+/// What the client remembers between runs, usually stored next to `config.conf`
+#[derive(Default, Serialize, Deserialize)]
+pub struct State {
+    /// Files that came from the server. Only these can be evicted to fit `max_size`,
+    /// anything else in the music directory was added by the user.
+    #[serde(default)]
+    pub downloaded: BTreeSet<String>,
+    /// Server names that had to be changed to be written on this filesystem
+    #[serde(default)]
+    pub names: NameMap,
+    /// The uploads that failed the last time, with what the server answered
+    #[serde(default)]
+    pub uploads: BTreeMap<String, UploadResult>,
+}
+
+impl State {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(buffer) => serde_json::from_str(&buffer)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Files whose last upload failed, they are retried on the next sync
+    pub fn pending_uploads(&self) -> impl Iterator<Item = &String> {
+        self.uploads
+            .iter()
+            .filter(|(_, result)| matches!(result, UploadResult::Failed { .. }))
+            .map(|(name, _)| name)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let buffer = serde_json::to_string_pretty(self)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        // write to a temporary file first so a crash never leaves a truncated state behind
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, buffer)?;
+        fs::rename(temp_path, path)
+    }
+}
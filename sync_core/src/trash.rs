@@ -0,0 +1,75 @@
+//! The versions the server keeps of the files that were replaced or deleted, in the
+//! library the config syncs
+
+use utils::{
+    encryption::TokenVerifier,
+    protocol::{ErrorResponse, FileVersion},
+};
+
+use crate::{events::Events, Config, Error};
+
+/// The versions in the trash, of every file or of only `name`, by name and newest first
+pub async fn versions(
+    config: &Config,
+    name: Option<&str>,
+    events: &Events,
+) -> Result<Vec<FileVersion>, Error> {
+    let client = reqwest::Client::new();
+    let token = TokenVerifier::new(&config.token).encrypt(config.token.as_bytes());
+    let response = config
+        .retry
+        .send(
+            || {
+                let request = client
+                    .get(config.url("trash"))
+                    .header("Authorization", &token);
+                match name {
+                    Some(name) => request.query(&[("name", name)]),
+                    None => request,
+                }
+            },
+            events,
+        )
+        .await?;
+
+    read_json(response).await
+}
+
+/// Puts a version back in the library, the file it replaces goes to the trash in turn
+pub async fn restore(
+    config: &Config,
+    name: &str,
+    version: u64,
+    events: &Events,
+) -> Result<FileVersion, Error> {
+    let client = reqwest::Client::new();
+    let token = TokenVerifier::new(&config.token).encrypt(config.token.as_bytes());
+    let version = version.to_string();
+    let response = config
+        .retry
+        .send(
+            || {
+                client
+                    .post(config.url("trash/restore"))
+                    .header("Authorization", &token)
+                    .query(&[("name", name), ("version", &version)])
+            },
+            events,
+        )
+        .await?;
+
+    read_json(response).await
+}
+
+async fn read_json<T: serde::de::DeserializeOwned>(
+    response: reqwest::Response,
+) -> Result<T, Error> {
+    let status = response.status();
+    let body = response.bytes().await?;
+    if !status.is_success() {
+        let message = ErrorResponse::describe(&body);
+        return Err(Error::Server { status, message });
+    }
+
+    serde_json::from_slice(&body).map_err(|err| Error::InvalidResponse(std::io::Error::other(err)))
+}
@@ -0,0 +1,172 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use reqwest::{Body, RequestBuilder};
+use utils::{
+    bandwidth::{Limiter, CHUNK_SIZE},
+    cbf::{ENTRY_OVERHEAD as CBF_ENTRY_OVERHEAD, HEADER_SIZE as CBF_HEADER_SIZE},
+    protocol::{ErrorResponse, SyncResponse, UploadResult},
+};
+
+use crate::{
+    events::{Events, Progress},
+    retry::RetryPolicy,
+};
+
+/// Splits `files` in batches whose CBF encoding stays under `limit`. The second list holds
+/// the files that are too large to ever be uploaded.
+pub fn batches<'a, V: AsRef<Vec<u8>>>(
+    files: &HashMap<&'a String, V>,
+    limit: Option<u64>,
+) -> (Vec<Vec<&'a String>>, Vec<&'a String>) {
+    let Some(limit) = limit else {
+        return (vec![files.keys().copied().collect()], Vec::new());
+    };
+
+    let mut names: Vec<&String> = files.keys().copied().collect();
+    names.sort();
+
+    let mut batches = Vec::new();
+    let mut too_large = Vec::new();
+    let mut batch = Vec::new();
+    let mut batch_size = CBF_HEADER_SIZE;
+
+    for name in names {
+        let size = entry_size(name, &files[name]);
+        if CBF_HEADER_SIZE + size > limit {
+            too_large.push(name);
+            continue;
+        }
+        if batch_size + size > limit {
+            batches.push(std::mem::take(&mut batch));
+            batch_size = CBF_HEADER_SIZE;
+        }
+        batch.push(name);
+        batch_size += size;
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+
+    (batches, too_large)
+}
+
+fn entry_size<V: AsRef<Vec<u8>>>(name: &str, data: &V) -> u64 {
+    data.as_ref().len() as u64 + name.len() as u64 + CBF_ENTRY_OVERHEAD
+}
+
+/// The size of the CBF encoding of `batch`
+pub fn encoded_size<V: AsRef<Vec<u8>>>(files: &HashMap<&String, V>, batch: &[&String]) -> u64 {
+    CBF_HEADER_SIZE
+        + batch
+            .iter()
+            .map(|name| entry_size(name, &files[*name]))
+            .sum::<u64>()
+}
+
+/// Streams `data` in chunks as fast as `limiter` allows, adding what was sent to
+/// `progress` and `sent`
+pub fn body(
+    data: Arc<[u8]>,
+    limiter: Arc<Limiter>,
+    progress: Arc<Progress>,
+    sent: Arc<AtomicU64>,
+) -> Body {
+    let chunks = futures::stream::unfold(0, move |offset| {
+        let (data, limiter, progress, sent) = (
+            data.clone(),
+            limiter.clone(),
+            progress.clone(),
+            sent.clone(),
+        );
+        async move {
+            if offset >= data.len() {
+                return None;
+            }
+
+            let end = data.len().min(offset + CHUNK_SIZE);
+            let length = (end - offset) as u64;
+            let delay = limiter.delay(length);
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+
+            progress.add_bytes(length);
+            sent.fetch_add(length, Ordering::Relaxed);
+            Some((Ok::<_, io::Error>(data[offset..end].to_vec()), end))
+        }
+    });
+
+    Body::wrap_stream(chunks)
+}
+
+/// Sends an upload, retrying as `retry` allows, and returns the result of every file
+pub async fn send<F>(
+    request: F,
+    retry: &RetryPolicy,
+    events: &Events,
+) -> Result<BTreeMap<String, UploadResult>, String>
+where
+    F: FnMut() -> RequestBuilder,
+{
+    let response = retry
+        .send(request, events)
+        .await
+        .map_err(|err| err.to_string())?;
+    let status = response.status();
+
+    let body = response.bytes().await.map_err(|err| err.to_string())?;
+    if !status.is_success() {
+        return Err(format!("{} {}", status, ErrorResponse::describe(&body)));
+    }
+
+    SyncResponse::read(&mut body.as_ref())
+        .map(|response| response.uploads)
+        .map_err(|err| format!("Invalid response from the server: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use utils::cbf;
+
+    use super::*;
+
+    #[test]
+    fn test_batches() {
+        let names = ["a.mp3", "b.mp3", "c.mp3", "huge.flac"].map(String::from);
+        let sizes = [40, 40, 10, 200];
+        let files: HashMap<&String, Vec<u8>> = names
+            .iter()
+            .zip(sizes)
+            .map(|(name, size)| (name, vec![0; size]))
+            .collect();
+
+        let (batches, too_large) = batches(&files, Some(100));
+        assert_eq!(batches, [vec!["a.mp3"], vec!["b.mp3", "c.mp3"]]);
+        assert_eq!(too_large, ["huge.flac"]);
+
+        let mut buffer = Vec::new();
+        let batch_files: HashMap<_, _> = batches[1]
+            .iter()
+            .map(|name| (*name, &files[*name]))
+            .collect();
+        cbf::write(
+            &mut buffer,
+            &batch_files,
+            None::<&std::collections::HashSet<&String>>,
+        )
+        .unwrap();
+        assert_eq!(encoded_size(&files, &batches[1]), buffer.len() as u64);
+
+        let (batches, too_large) = super::batches(&files, None);
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].len(), 4);
+        assert!(too_large.is_empty());
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::Timelike;
+
+/// Limited streams are read in pieces of at most this size, so waits stay short
+pub const CHUNK_SIZE: usize = 16 * 1024;
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// A rate limit in bytes per second that can change with the time of day, written like
+/// `1M, 01:00-06:00 unlimited, 18:00-23:00 200K`. The windows are in local time and the
+/// first one that contains the current time wins, outside of them the default applies.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Schedule {
+    /// `None` is unlimited
+    default: Option<u64>,
+    windows: Vec<Window>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Window {
+    /// Minutes since midnight, a window that ends before it starts wraps around midnight
+    start: u16,
+    end: u16,
+    limit: Option<u64>,
+}
+
+impl Schedule {
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut schedule = Self::default();
+        let mut has_default = false;
+
+        for part in value.split(',').map(str::trim) {
+            match part.split_once(' ') {
+                Some((range, limit)) if range.contains('-') => {
+                    let (start, end) = range.split_once('-')?;
+                    schedule.windows.push(Window {
+                        start: parse_time(start)?,
+                        end: parse_time(end)?,
+                        limit: parse_limit(limit.trim())?,
+                    });
+                }
+                _ if !has_default => {
+                    schedule.default = parse_limit(part)?;
+                    has_default = true;
+                }
+                _ => return None,
+            }
+        }
+
+        Some(schedule)
+    }
+
+    /// Whether no limit ever applies
+    pub fn is_unlimited(&self) -> bool {
+        self.default.is_none() && self.windows.iter().all(|window| window.limit.is_none())
+    }
+
+    /// The limit at `minute` minutes after midnight
+    pub fn limit_at(&self, minute: u16) -> Option<u64> {
+        let window = self.windows.iter().find(|window| {
+            if window.start <= window.end {
+                (window.start..window.end).contains(&minute)
+            } else {
+                minute >= window.start || minute < window.end
+            }
+        });
+
+        match window {
+            Some(window) => window.limit,
+            None => self.default,
+        }
+    }
+
+    pub fn limit_now(&self) -> Option<u64> {
+        let now = chrono::Local::now();
+        self.limit_at((now.hour() * 60 + now.minute()) as u16)
+    }
+}
+
+/// `HH:MM`, `24:00` is the end of the day
+fn parse_time(value: &str) -> Option<u16> {
+    let (hours, minutes) = value.trim().split_once(':')?;
+    let (hours, minutes): (u16, u16) = (hours.parse().ok()?, minutes.parse().ok()?);
+    let time = hours.checked_mul(60)?.checked_add(minutes)?;
+
+    (minutes < 60 && time <= MINUTES_PER_DAY).then_some(time)
+}
+
+/// `unlimited` or a size per second, see `parse_size`
+fn parse_limit(value: &str) -> Option<Option<u64>> {
+    if value == "unlimited" {
+        return Some(None);
+    }
+
+    let value = value.strip_suffix("/s").unwrap_or(value);
+    crate::parse_size(value)
+        .filter(|limit| *limit > 0)
+        .map(Some)
+}
+
+/// Holds up to one second worth of bytes, so short bursts go through at full speed
+#[derive(Debug)]
+pub struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new() -> Self {
+        Self {
+            tokens: f64::INFINITY,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes `bytes` out of the bucket filling at `rate` bytes per second and returns how
+    /// long to wait before sending them. A `None` rate never waits.
+    pub fn take(&mut self, bytes: u64, rate: Option<u64>, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+
+        let Some(rate) = rate else {
+            // the bucket is full again once a limit applies
+            self.tokens = f64::INFINITY;
+            return Duration::ZERO;
+        };
+
+        let rate = rate as f64;
+        self.tokens = (self.tokens + elapsed * rate).min(rate) - bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A schedule and the bucket it fills, shared by everything one limit applies to
+#[derive(Debug)]
+pub struct Limiter {
+    schedule: Schedule,
+    bucket: Mutex<TokenBucket>,
+}
+
+impl Limiter {
+    pub fn new(schedule: Schedule) -> Self {
+        Self {
+            schedule,
+            bucket: Mutex::new(TokenBucket::new()),
+        }
+    }
+
+    /// How long to wait before sending `bytes` right now
+    pub fn delay(&self, bytes: u64) -> Duration {
+        let limit = self.schedule.limit_now();
+        self.bucket
+            .lock()
+            .unwrap()
+            .take(bytes, limit, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        let schedule = Schedule::parse("1M, 01:00-06:00 unlimited, 22:00-02:00 200K/s").unwrap();
+        assert_eq!(schedule.limit_at(0), Some(200 * 1024));
+        assert_eq!(schedule.limit_at(90), None);
+        assert_eq!(schedule.limit_at(6 * 60), Some(1024 * 1024));
+        assert_eq!(schedule.limit_at(23 * 60), Some(200 * 1024));
+        assert!(!schedule.is_unlimited());
+
+        let schedule = Schedule::parse("01:00-06:00 512K").unwrap();
+        assert_eq!(schedule.limit_at(0), None);
+        assert_eq!(schedule.limit_at(60), Some(512 * 1024));
+
+        assert!(Schedule::parse("unlimited").unwrap().is_unlimited());
+        assert!(Schedule::parse("1M, 2M").is_none());
+        assert!(Schedule::parse("0").is_none());
+        assert!(Schedule::parse("25:00-06:00 1M").is_none());
+        assert!(Schedule::parse("2000:00-06:00 1M").is_none());
+        assert!(Schedule::parse("01:00-1093:00 1M").is_none());
+        assert!(Schedule::parse("01:00-06:00 fast").is_none());
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new();
+
+        // a full bucket lets one second worth through
+        assert_eq!(bucket.take(1000, Some(1000), start), Duration::ZERO);
+        assert_eq!(
+            bucket.take(500, Some(1000), start),
+            Duration::from_millis(500)
+        );
+
+        // the debt is paid back over time
+        let later = start + Duration::from_millis(500);
+        assert_eq!(
+            bucket.take(100, Some(1000), later),
+            Duration::from_millis(100)
+        );
+
+        assert_eq!(bucket.take(u64::MAX, None, later), Duration::ZERO);
+        assert_eq!(bucket.take(1000, Some(1000), later), Duration::ZERO);
+    }
+}
@@ -5,6 +5,13 @@ use std::{
 
 pub type FileEntries = HashMap<String, Vec<u8>>;
 
+/// The u16 count of missing files at the start
+pub const HEADER_SIZE: u64 = 2;
+/// The u64 size and u16 name length before every entry
+pub const ENTRY_OVERHEAD: u64 = 10;
+
+/// Fails with `InvalidInput` for names longer than 65535 bytes or more than 65535
+/// missing files, nothing is written that `read` would get wrong
 pub fn write<W, V, S>(
     writer: &mut W,
     entries: &HashMap<S, V>,
@@ -16,60 +23,83 @@ where
     S: AsRef<str>,
 {
     if let Some(missing_files) = missing_files {
-        writer.write_all(&(missing_files.len() as u16).to_le_bytes())?;
+        let count = u16::try_from(missing_files.len())
+            .map_err(|_| invalid_input("Too many missing files".to_string()))?;
+        writer.write_all(&count.to_le_bytes())?;
 
         for missing_file in missing_files {
-            let name_length = missing_file.as_ref().len() as u8;
-            writer.write_all(&[name_length])?;
-            writer.write_all(missing_file.as_ref().as_bytes())?;
+            write_name(writer, missing_file.as_ref())?;
         }
     } else {
         writer.write_all(&0u16.to_le_bytes())?;
     }
 
-    for (name, data) in entries.into_iter() {
-        let file_size = data.as_ref().len() as u32;
+    for (name, data) in entries.iter() {
+        let file_size = data.as_ref().len() as u64;
         writer.write_all(&file_size.to_le_bytes())?;
 
-        let name_length = name.as_ref().len() as u8;
-        writer.write_all(&[name_length])?;
-
-        writer.write_all(name.as_ref().as_bytes())?;
+        write_name(writer, name.as_ref())?;
 
-        writer.write_all(&data.as_ref())?;
+        writer.write_all(data.as_ref())?;
     }
 
     Ok(())
 }
 
+fn write_name<W: Write>(writer: &mut W, name: &str) -> io::Result<()> {
+    let name_length = u16::try_from(name.len())
+        .map_err(|_| invalid_input(format!("A name is {} bytes long", name.len())))?;
+    writer.write_all(&name_length.to_le_bytes())?;
+    writer.write_all(name.as_bytes())
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Names are normalized to NFC, two entries that only differ in normalization are an error
 pub fn read<R: Read>(reader: &mut R) -> io::Result<(HashSet<String>, FileEntries)> {
     let mut missing_files = HashSet::new();
     let missing_files_count = u16::from_le_bytes(read_n_bytes(reader, 2)?.try_into().unwrap());
 
     for _ in 0..missing_files_count {
-        let name_length = read_n_bytes(reader, 1)?[0] as usize;
-        let name_bytes = read_n_bytes(reader, name_length)?;
-        let name = String::from_utf8(name_bytes)
-            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8"))?;
+        let name = read_name(reader)?;
         missing_files.insert(name);
     }
 
     let mut entries = HashMap::new();
-    while let Ok(file_size_bytes) = read_n_bytes(reader, 4) {
-        let file_size = u32::from_le_bytes(file_size_bytes.try_into().unwrap());
-
-        let name_length = read_n_bytes(reader, 1)?[0] as usize;
-        let name_bytes = read_n_bytes(reader, name_length)?;
-        let name = String::from_utf8(name_bytes)
-            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8"))?;
-        let mut data = vec![0u8; file_size as usize];
-        reader.read_exact(&mut data)?;
+    // the file ends where an entry would start, anywhere else it was cut short
+    let mut size = [0; 8];
+    while reader.read(&mut size[..1])? > 0 {
+        reader.read_exact(&mut size[1..])?;
+        let file_size = u64::from_le_bytes(size);
+
+        let name = read_name(reader)?;
+        let mut data = Vec::new();
+        reader.take(file_size).read_to_end(&mut data)?;
+        if data.len() as u64 != file_size {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
 
+        if entries.contains_key(&name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Duplicate entry {:?}", name),
+            ));
+        }
         entries.insert(name, data);
     }
     Ok((missing_files, entries))
 }
 
+fn read_name<R: Read>(reader: &mut R) -> io::Result<String> {
+    let name_length = u16::from_le_bytes(read_n_bytes(reader, 2)?.try_into().unwrap()) as usize;
+    let name_bytes = read_n_bytes(reader, name_length)?;
+    let name = String::from_utf8(name_bytes)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8"))?;
+    Ok(crate::normalize_name(&name))
+}
+
 fn read_n_bytes<R: Read>(reader: &mut R, n: usize) -> io::Result<Vec<u8>> {
     let mut buffer = vec![0u8; n];
     reader.read_exact(&mut buffer)?;
@@ -109,6 +139,54 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_read_normalizes_names() {
+        let mut entries = HashMap::new();
+        entries.insert("Cafe\u{301}.mp3".to_string(), vec![0x01]);
+
+        let mut buffer = Vec::new();
+        write(&mut buffer, &entries, None).expect("Failed to write custom format");
+
+        let (_, read_entries) =
+            read(&mut std::io::Cursor::new(&buffer)).expect("Failed to read custom format");
+        assert!(read_entries.contains_key("Caf\u{e9}.mp3"));
+
+        entries.insert("Caf\u{e9}.mp3".to_string(), vec![0x02]);
+        let mut buffer = Vec::new();
+        write(&mut buffer, &entries, None).expect("Failed to write custom format");
+
+        let error = read(&mut std::io::Cursor::new(&buffer)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_long_names() {
+        let name = "é".repeat(200);
+        let entries = HashMap::from([(name.clone(), vec![0x01])]);
+        let mut buffer = Vec::new();
+        write(&mut buffer, &entries, None).unwrap();
+        let (_, read_entries) = read(&mut buffer.as_slice()).unwrap();
+        assert_eq!(read_entries[&name], vec![0x01]);
+
+        let entries = HashMap::from([("a".repeat(70_000), vec![0x01])]);
+        let error = write(&mut Vec::new(), &entries, None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_read_truncated() {
+        let entries = HashMap::from([("song.mp3".to_string(), vec![0x01; 100])]);
+        let mut buffer = Vec::new();
+        write(&mut buffer, &entries, None).unwrap();
+
+        // cut in the size, in the name and in the data
+        for length in [HEADER_SIZE as usize + 3, 13, buffer.len() - 1] {
+            let error = read(&mut &buffer[..length]).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        }
+        assert_eq!(buffer.len() as u64, HEADER_SIZE + ENTRY_OVERHEAD + 8 + 100);
+    }
+
     #[test]
     fn test_write_read_no_missing_files() {
         let mut entries = HashMap::new();
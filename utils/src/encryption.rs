@@ -104,6 +104,6 @@ mod tests {
 
         // Verify with a modified token
         let wrong_token = "wrong_token";
-        assert!(!verifier.verify(&wrong_token));
+        assert!(!verifier.verify(wrong_token));
     }
 }
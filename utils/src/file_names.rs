@@ -0,0 +1,245 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fmt::Write,
+    path::{Component, Path, PathBuf},
+};
+
+/// What to do with file names that aren't valid UTF-8
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum NonUtf8Names {
+    /// Keep them, escaped with `encode`
+    #[default]
+    Escape,
+    /// Print a warning and leave the file out of the sync
+    Skip,
+}
+
+impl NonUtf8Names {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "escape" => Some(Self::Escape),
+            "skip" => Some(Self::Skip),
+            _ => None,
+        }
+    }
+}
+
+enum Token {
+    Char(char),
+    /// `%XX`, a byte that wasn't part of valid UTF-8
+    Byte(u8),
+    /// `%uXXXX`, an unpaired UTF-16 surrogate from a Windows name
+    Unit(#[cfg_attr(not(windows), allow(dead_code))] u16),
+}
+
+fn hex_at(bytes: &[u8], start: usize, digits: usize) -> Option<u32> {
+    let hex = bytes.get(start..start + digits)?;
+    if !hex.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    u32::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
+}
+
+fn tokens(name: &str) -> Vec<Token> {
+    let bytes = name.as_bytes();
+    let mut tokens = Vec::new();
+    let mut chars = name.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        let escape = if c == '%' {
+            if let Some(byte) = hex_at(bytes, i + 1, 2) {
+                Some((Token::Byte(byte as u8), 2))
+            } else if bytes.get(i + 1) == Some(&b'u') {
+                hex_at(bytes, i + 2, 4).map(|unit| (Token::Unit(unit as u16), 5))
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        match escape {
+            Some((token, length)) => {
+                tokens.push(token);
+                for _ in 0..length {
+                    chars.next();
+                }
+            }
+            None => tokens.push(Token::Char(c)),
+        }
+    }
+
+    tokens
+}
+
+/// A `%` that would be read back as an escape is escaped itself, any other `%` is kept
+/// as is so that the names that were already valid UTF-8 rarely change
+fn push_text(output: &mut String, text: &str) {
+    let bytes = text.as_bytes();
+
+    for (i, c) in text.char_indices() {
+        let looks_like_escape = c == '%'
+            && (hex_at(bytes, i + 1, 2).is_some()
+                || (bytes.get(i + 1) == Some(&b'u') && hex_at(bytes, i + 2, 4).is_some()));
+
+        if looks_like_escape {
+            output.push_str("%25");
+        } else {
+            output.push(c);
+        }
+    }
+}
+
+/// Losslessly turns a file name into a string that can go in the manifest and in CBF files
+#[cfg(unix)]
+pub fn encode(name: &OsStr) -> String {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut output = String::new();
+    let mut rest = name.as_bytes();
+
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                push_text(&mut output, valid);
+                return output;
+            }
+            Err(err) => {
+                let (valid, invalid) = rest.split_at(err.valid_up_to());
+                push_text(&mut output, std::str::from_utf8(valid).unwrap());
+
+                let invalid_length = err.error_len().unwrap_or(invalid.len());
+                for byte in &invalid[..invalid_length] {
+                    write!(output, "%{:02X}", byte).unwrap();
+                }
+                rest = &invalid[invalid_length..];
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+pub fn encode(name: &OsStr) -> String {
+    use std::os::windows::ffi::OsStrExt;
+
+    let mut output = String::new();
+    let mut text = String::new();
+
+    for result in char::decode_utf16(name.encode_wide()) {
+        match result {
+            Ok(c) => text.push(c),
+            Err(err) => {
+                push_text(&mut output, &std::mem::take(&mut text));
+                write!(output, "%u{:04X}", err.unpaired_surrogate()).unwrap();
+            }
+        }
+    }
+    push_text(&mut output, &text);
+
+    output
+}
+
+/// The inverse of `encode`, `None` if the name can't be represented on this platform
+/// (e.g. raw bytes on Windows or surrogates on Unix)
+#[cfg(unix)]
+pub fn decode(name: &str) -> Option<OsString> {
+    use std::os::unix::ffi::OsStringExt;
+
+    let mut bytes = Vec::with_capacity(name.len());
+    for token in tokens(name) {
+        match token {
+            Token::Char(c) => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            Token::Byte(byte) => bytes.push(byte),
+            Token::Unit(_) => return None,
+        }
+    }
+
+    Some(OsString::from_vec(bytes))
+}
+
+#[cfg(windows)]
+pub fn decode(name: &str) -> Option<OsString> {
+    use std::os::windows::ffi::OsStringExt;
+
+    let mut units = Vec::with_capacity(name.len());
+    let mut bytes = Vec::new();
+
+    for token in tokens(name) {
+        if !matches!(token, Token::Byte(_)) && !bytes.is_empty() {
+            units.extend(std::str::from_utf8(&bytes).ok()?.encode_utf16());
+            bytes.clear();
+        }
+        match token {
+            Token::Char(c) => units.extend(c.encode_utf16(&mut [0; 2]).iter()),
+            Token::Byte(byte) => bytes.push(byte),
+            Token::Unit(unit) => units.push(unit),
+        }
+    }
+    units.extend(std::str::from_utf8(&bytes).ok()?.encode_utf16());
+
+    Some(OsString::from_wide(&units))
+}
+
+/// Where the file with the (encoded) `name` lives in `dir`. Libraries are flat, so names
+/// that aren't a single plain component (separators, `.`, `..`, roots or prefixes) are
+/// refused instead of pointing outside of `dir`.
+pub fn path(dir: &str, name: &str) -> Option<PathBuf> {
+    let name = decode(name)?;
+    let mut components = Path::new(&name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(component)), None) if component == name.as_os_str() => {
+            Some(Path::new(dir).join(name))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn test_roundtrip() {
+        let names: [&[u8]; 5] = [
+            b"plain.mp3",
+            b"caf\xe9.mp3",
+            b"100% hits.mp3",
+            b"literal %41 escape.mp3",
+            b"\xff\xfe%u00e9",
+        ];
+
+        for name in names {
+            let encoded = encode(OsStr::from_bytes(name));
+            assert_eq!(decode(&encoded).unwrap().as_bytes(), name);
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(OsStr::new("plain.mp3")), "plain.mp3");
+        assert_eq!(encode(OsStr::new("100% hits.mp3")), "100% hits.mp3");
+        assert_eq!(encode(OsStr::from_bytes(b"caf\xe9.mp3")), "caf%E9.mp3");
+        assert_eq!(encode(OsStr::new("%41.mp3")), "%2541.mp3");
+        assert!(decode("%uD800.mp3").is_none());
+    }
+
+    #[test]
+    fn test_path() {
+        assert_eq!(
+            path("/music", "song.mp3"),
+            Some(PathBuf::from("/music/song.mp3"))
+        );
+        for name in [
+            "../podcasts/x.mp3",
+            "..",
+            ".",
+            "a/b.mp3",
+            "/etc/passwd",
+            "",
+            "%2E%2E",
+        ] {
+            assert_eq!(path("/music", name), None, "{:?}", name);
+        }
+    }
+}
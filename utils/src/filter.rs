@@ -0,0 +1,328 @@
+use std::fmt;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{playlist, tags::TrackInfo};
+
+/// Decides which files take part in a sync.
+/// A file is synced if it matches one of the include patterns (or there are none),
+/// none of the exclude patterns and every tag rule.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SyncFilter {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub rules: Vec<TagRule>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Equal,
+    NotEqual,
+    Contains,
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+}
+
+impl Operator {
+    const ALL: [(&'static str, Operator); 7] = [
+        ("==", Operator::Equal),
+        ("!=", Operator::NotEqual),
+        ("~=", Operator::Contains),
+        (">=", Operator::GreaterOrEqual),
+        ("<=", Operator::LessOrEqual),
+        (">", Operator::Greater),
+        ("<", Operator::Less),
+    ];
+
+    fn as_str(self) -> &'static str {
+        Self::ALL
+            .iter()
+            .find(|(_, operator)| *operator == self)
+            .map(|(symbol, _)| *symbol)
+            .unwrap()
+    }
+}
+
+/// A comparison against a tag, e.g. `genre != "Audiobook"` or `year >= 2000`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagRule {
+    pub field: String,
+    pub operator: Operator,
+    pub value: String,
+}
+
+impl TagRule {
+    pub fn parse(rule: &str) -> Option<Self> {
+        let (position, symbol, operator) = Operator::ALL
+            .iter()
+            .filter_map(|(symbol, operator)| Some((rule.find(symbol)?, *symbol, *operator)))
+            .min_by_key(|(position, symbol, _)| (*position, usize::MAX - symbol.len()))?;
+
+        let field = rule[..position].trim().to_lowercase();
+        let value = rule[position + symbol.len()..].trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+
+        if field.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            field,
+            operator,
+            value: value.to_string(),
+        })
+    }
+
+    /// Files without the tag only pass `!=` rules
+    pub fn matches(&self, info: &TrackInfo) -> bool {
+        let Some(actual) = info.field(&self.field) else {
+            return self.operator == Operator::NotEqual;
+        };
+
+        let numbers = actual
+            .parse::<f64>()
+            .ok()
+            .zip(self.value.parse::<f64>().ok());
+
+        match (self.operator, numbers) {
+            (Operator::Equal, _) => actual.eq_ignore_ascii_case(&self.value),
+            (Operator::NotEqual, _) => !actual.eq_ignore_ascii_case(&self.value),
+            (Operator::Contains, _) => actual.to_lowercase().contains(&self.value.to_lowercase()),
+            (Operator::Greater, Some((actual, value))) => actual > value,
+            (Operator::GreaterOrEqual, Some((actual, value))) => actual >= value,
+            (Operator::Less, Some((actual, value))) => actual < value,
+            (Operator::LessOrEqual, Some((actual, value))) => actual <= value,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for TagRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} \"{}\"",
+            self.field,
+            self.operator.as_str(),
+            self.value
+        )
+    }
+}
+
+impl SyncFilter {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty() && self.rules.is_empty()
+    }
+
+    /// Adds an `include`, `exclude` or `rule` setting, returns false for any other key
+    pub fn add(&mut self, key: &str, value: &str) -> bool {
+        match key {
+            "include" => self.include.push(value.to_string()),
+            "exclude" => self.exclude.push(value.to_string()),
+            "rule" => match TagRule::parse(value) {
+                Some(rule) => self.rules.push(rule),
+                None => return false,
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    /// Parses the text produced by the `Display` implementation, one setting per line
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut filter = Self::default();
+
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let (key, value) = line.split_once(' ')?;
+            if !filter.add(key, value) {
+                return None;
+            }
+        }
+
+        Some(filter)
+    }
+
+    /// `info` is only needed when there are tag rules, `None` fails all of them except `!=`.
+    /// Playlists have no tags, so only the patterns apply to them.
+    pub fn matches(&self, name: &str, info: Option<&TrackInfo>) -> bool {
+        let included =
+            self.include.is_empty() || self.include.iter().any(|pattern| glob_match(pattern, name));
+        let excluded = self.exclude.iter().any(|pattern| glob_match(pattern, name));
+
+        if !included || excluded {
+            return false;
+        }
+        if playlist::is_playlist(name) {
+            return true;
+        }
+
+        let default = TrackInfo::default();
+        let info = info.unwrap_or(&default);
+        self.rules.iter().all(|rule| rule.matches(info))
+    }
+}
+
+impl fmt::Display for SyncFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for pattern in &self.include {
+            writeln!(f, "include {}", pattern)?;
+        }
+        for pattern in &self.exclude {
+            writeln!(f, "exclude {}", pattern)?;
+        }
+        for rule in &self.rules {
+            writeln!(f, "rule {}", rule)?;
+        }
+        Ok(())
+    }
+}
+
+/// Filters are sent in the sync manifest in their text form
+impl Serialize for SyncFilter {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for SyncFilter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        Self::parse(&text).ok_or_else(|| de::Error::custom("invalid sync filter"))
+    }
+}
+
+/// Shell style wildcards: `*` matches any run of characters, `?` a single one
+/// and `[abc]`, `[a-z]` or `[!abc]` a character class
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // where to resume after the last `*` if the rest fails to match
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match_class(&pattern[p..], text[t]),
+            Some(c) if *c == text[t] => Some(1),
+            _ => None,
+        };
+
+        match step {
+            Some(length) => {
+                p += length;
+                t += 1;
+            }
+            None => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Returns the length of the class in the pattern if `c` belongs to it
+fn match_class(pattern: &[char], c: char) -> Option<usize> {
+    let end = pattern.iter().skip(2).position(|c| *c == ']')? + 2;
+    let (negated, class) = match pattern[1] {
+        '!' | '^' => (true, &pattern[2..end]),
+        _ => (false, &pattern[1..end]),
+    };
+
+    let mut found = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == '-' {
+            found |= (class[i]..=class[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            found |= class[i] == c;
+            i += 1;
+        }
+    }
+
+    (found != negated).then_some(end + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.flac", "song.flac"));
+        assert!(!glob_match("*.flac", "song.mp3"));
+        assert!(glob_match("*live*", "01 - live at wembley.mp3"));
+        assert!(glob_match("track??.mp3", "track01.mp3"));
+        assert!(glob_match("[0-9]*", "1 song.mp3"));
+        assert!(!glob_match("[!0-9]*", "1 song.mp3"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("a*b", "acbd"));
+    }
+
+    #[test]
+    fn test_tag_rule() {
+        let rule = TagRule::parse(r#"genre != "Audiobook""#).unwrap();
+        assert_eq!(rule.field, "genre");
+        assert_eq!(rule.operator, Operator::NotEqual);
+        assert_eq!(rule.value, "Audiobook");
+
+        let audiobook = TrackInfo {
+            genre: Some("audiobook".to_string()),
+            year: Some(1995),
+            ..Default::default()
+        };
+        assert!(!rule.matches(&audiobook));
+        assert!(rule.matches(&TrackInfo::default()));
+
+        assert!(TagRule::parse("year >= 1990").unwrap().matches(&audiobook));
+        assert!(!TagRule::parse("year > 1995").unwrap().matches(&audiobook));
+        assert!(TagRule::parse("invalid").is_none());
+    }
+
+    #[test]
+    fn test_sync_filter() {
+        let mut filter = SyncFilter::default();
+        assert!(filter.add("include", "*.flac"));
+        assert!(filter.add("exclude", "*demo*"));
+        assert!(filter.add("rule", "genre != Audiobook"));
+        assert!(!filter.add("unknown", "value"));
+
+        assert!(filter.matches("song.flac", None));
+        assert!(!filter.matches("song.mp3", None));
+        assert!(!filter.matches("song demo.flac", None));
+
+        let audiobook = TrackInfo {
+            genre: Some("Audiobook".to_string()),
+            ..Default::default()
+        };
+        assert!(!filter.matches("book.flac", Some(&audiobook)));
+        assert!(!filter.matches("list.m3u", None));
+
+        filter.include.push("*.m3u".to_string());
+        assert!(filter.matches("list.m3u", None));
+
+        let parsed = SyncFilter::parse(&filter.to_string()).unwrap();
+        assert_eq!(parsed, filter);
+
+        let json = serde_json::to_string(&filter).unwrap();
+        assert_eq!(serde_json::from_str::<SyncFilter>(&json).unwrap(), filter);
+    }
+}
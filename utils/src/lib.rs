@@ -1,10 +1,26 @@
-use std::{collections::HashSet, fs, io};
+use std::{collections::HashMap, ffi::OsString, fs, io};
 
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+pub mod bandwidth;
 pub mod cbf;
 pub mod encryption;
-pub mod split_strings;
-
-pub fn get_files(path: &str) -> io::Result<(HashSet<String>, cbf::FileEntries)> {
+pub mod file_names;
+pub mod filter;
+pub mod playlist;
+pub mod protocol;
+pub mod tags;
+
+/// The name each file has on disk, by its NFC name. Paths have to be built from these,
+/// an NFD name doesn't find the file on most filesystems.
+pub type DiskNames = HashMap<String, OsString>;
+
+/// Reads every file in `path`, names that aren't valid UTF-8 are escaped with
+/// `file_names::encode` or skipped depending on `non_utf8_names`
+pub fn get_files(
+    path: &str,
+    non_utf8_names: file_names::NonUtf8Names,
+) -> io::Result<(DiskNames, cbf::FileEntries)> {
     let path = if let Ok(path) = fs::read_dir(path) {
         path
     } else {
@@ -13,7 +29,8 @@ pub fn get_files(path: &str) -> io::Result<(HashSet<String>, cbf::FileEntries)>
     };
 
     let mut entries = cbf::FileEntries::new();
-    let mut file_names = HashSet::new();
+    let mut disk_names = DiskNames::new();
+
     for path in path {
         let path = path?.path();
 
@@ -21,65 +38,135 @@ pub fn get_files(path: &str) -> io::Result<(HashSet<String>, cbf::FileEntries)>
             continue;
         }
 
-        let file_name = path.file_name().unwrap().to_str().unwrap().to_string();
+        let os_name = path.file_name().unwrap().to_owned();
+        if os_name.to_str().is_none() && non_utf8_names == file_names::NonUtf8Names::Skip {
+            eprintln!("Skipping {:?}, its name is not valid UTF-8", path);
+            continue;
+        }
 
-        file_names.insert(file_name.clone());
+        let disk_name = file_names::encode(&os_name);
+        let file_name = normalize_name(&disk_name);
+
+        // e.g. "é" written as one code point and as "e" plus a combining accent,
+        // the NFC one wins since that's what every other device will call it
+        if let Some(other) = disk_names.get(&file_name) {
+            let other = file_names::encode(other);
+            let keep_other = is_nfc(&other) || (!is_nfc(&disk_name) && other < disk_name);
+            eprintln!(
+                "Both {:?} and {:?} normalize to {:?}, ignoring {:?}",
+                other,
+                disk_name,
+                file_name,
+                if keep_other { &disk_name } else { &other }
+            );
+            if keep_other {
+                continue;
+            }
+        }
 
         let data = fs::read(path)?;
+
+        disk_names.insert(file_name.clone(), os_name);
         entries.insert(file_name, data);
     }
 
-    Ok((file_names, entries))
+    Ok((disk_names, entries))
 }
 
-pub fn join_hashset<S>(set: &HashSet<S>, separator: char) -> String
-where
-    S: AsRef<str>,
-{
-    let num_elements = set.len();
-    if num_elements == 0 {
-        return String::new();
-    }
-
-    let separator_len = 1;
-    let total_length: usize =
-        set.iter().map(|s| s.as_ref().len()).sum::<usize>() + (num_elements - 1) * separator_len;
-
-    let mut joined = String::with_capacity(total_length);
-
-    let mut set_iter = set.iter();
-
-    if let Some(first) = set_iter.next() {
-        joined.push_str(first.as_ref());
+/// File names are compared in NFC, macOS writes them in NFD while most other systems use NFC
+pub fn normalize_name(name: &str) -> String {
+    if is_nfc(name) {
+        name.to_string()
+    } else {
+        name.nfc().collect()
     }
+}
 
-    for item in set_iter {
-        joined.push(separator);
-        joined.push_str(item.as_ref());
-    }
+/// Parses sizes like `512`, `700M`, `32G` or `1.5T`, the units are powers of 1024
+pub fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1024,
+        "M" | "MB" | "MIB" => 1024 * 1024,
+        "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+        "T" | "TB" | "TIB" => 1024 * 1024 * 1024 * 1024,
+        _ => return None,
+    };
 
-    joined
+    let number: f64 = number.parse().ok()?;
+    Some((number * multiplier as f64) as u64)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashSet;
 
     #[test]
-    fn test_join_hashset() {
-        let set = ["file1", "file2", "file3"];
-        let set = set
-            .iter()
-            .map(|s| s.to_string())
-            .collect::<HashSet<String>>();
-
-        let result = join_hashset(&set, '|');
-        let result_set = result.split('|').collect::<HashSet<&str>>();
+    fn test_get_files_normalizes_names() {
+        let dir = std::env::temp_dir().join(format!("music_sync_nfc_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let nfd = "Cafe\u{301}.mp3";
+        let nfc = "Caf\u{e9}.mp3";
+        fs::write(dir.join(nfd), b"nfd").unwrap();
+        fs::write(dir.join(nfc), b"nfc").unwrap();
+
+        let (disk_names, entries) =
+            get_files(dir.to_str().unwrap(), file_names::NonUtf8Names::Escape).unwrap();
+        assert_eq!(disk_names, DiskNames::from([(nfc.to_string(), nfc.into())]));
+        assert_eq!(entries.get(nfc).unwrap(), b"nfc");
+
+        // a file only written in NFD is found under its NFC name, and its disk name
+        // still leads to it
+        fs::remove_file(dir.join(nfc)).unwrap();
+        let (disk_names, entries) =
+            get_files(dir.to_str().unwrap(), file_names::NonUtf8Names::Escape).unwrap();
+        assert_eq!(entries.get(nfc).unwrap(), b"nfd");
+        assert!(dir.join(&disk_names[nfc]).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-        // convert original set to a HashSet<&str> for comparison
-        let expected_set = set.iter().map(String::as_str).collect::<HashSet<&str>>();
+    #[cfg(unix)]
+    #[test]
+    fn test_get_files_non_utf8_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = std::env::temp_dir().join(format!("music_sync_latin1_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join(std::ffi::OsStr::from_bytes(b"caf\xe9.mp3")),
+            b"data",
+        )
+        .unwrap();
+
+        let (escaped, _) =
+            get_files(dir.to_str().unwrap(), file_names::NonUtf8Names::Escape).unwrap();
+        let (skipped, _) =
+            get_files(dir.to_str().unwrap(), file_names::NonUtf8Names::Skip).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            escaped.keys().collect::<Vec<_>>(),
+            vec![&"caf%E9.mp3".to_string()]
+        );
+        assert_eq!(
+            escaped["caf%E9.mp3"],
+            std::ffi::OsStr::from_bytes(b"caf\xe9.mp3")
+        );
+        assert!(skipped.is_empty());
+    }
 
-        assert_eq!(result_set, expected_set);
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("700M"), Some(700 * 1024 * 1024));
+        assert_eq!(parse_size("1.5 GB"), Some(1536 * 1024 * 1024));
+        assert_eq!(parse_size("12 parsecs"), None);
     }
 }
@@ -0,0 +1,263 @@
+/// Playlists are synced like any other file, but their entries are stored on the server
+/// relative to the library root and rewritten by each client to its own layout
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlaylistFormat {
+    M3u,
+    Pls,
+}
+
+impl PlaylistFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        let (_, extension) = name.rsplit_once('.')?;
+
+        match extension.to_ascii_lowercase().as_str() {
+            "m3u" | "m3u8" => Some(Self::M3u),
+            "pls" => Some(Self::Pls),
+            _ => None,
+        }
+    }
+}
+
+pub fn is_playlist(name: &str) -> bool {
+    PlaylistFormat::from_name(name).is_some()
+}
+
+/// How the client wants the entries of its playlists written
+#[derive(Debug, Clone)]
+pub struct PathStyle {
+    /// Prefix for absolute paths, `None` writes paths relative to the playlist
+    pub root: Option<String>,
+    pub separator: char,
+}
+
+/// M3U files are often Latin-1, anything that isn't valid UTF-8 is read as such
+pub fn decode(data: &[u8]) -> String {
+    match std::str::from_utf8(data) {
+        Ok(text) => text.to_string(),
+        Err(_) => data.iter().map(|byte| *byte as char).collect(),
+    }
+}
+
+/// Calls `rewrite` on every entry path, leaving comments and other lines untouched
+pub fn rewrite_paths(
+    text: &str,
+    format: PlaylistFormat,
+    mut rewrite: impl FnMut(&str) -> String,
+) -> String {
+    let line_ending = if text.contains("\r\n") { "\r\n" } else { "\n" };
+
+    let mut output = text
+        .lines()
+        .map(|line| match entry_path(line, format) {
+            Some((prefix, path)) => format!("{}{}", prefix, rewrite(path)),
+            None => line.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(line_ending);
+
+    if text.ends_with('\n') {
+        output.push_str(line_ending);
+    }
+    output
+}
+
+/// Splits an entry line into whatever comes before the path and the path itself
+fn entry_path(line: &str, format: PlaylistFormat) -> Option<(&str, &str)> {
+    let trimmed = line.trim();
+
+    match format {
+        PlaylistFormat::M3u if !trimmed.is_empty() && !trimmed.starts_with('#') => {
+            Some(("", trimmed))
+        }
+        PlaylistFormat::Pls => {
+            let (key, path) = trimmed.split_once('=')?;
+            let is_file = key
+                .strip_prefix("File")
+                .is_some_and(|number| number.chars().all(|c| c.is_ascii_digit()));
+            is_file.then(|| (&line[..line.find('=').unwrap() + 1], path.trim()))
+        }
+        _ => None,
+    }
+}
+
+/// Rewrites every entry of a playlist file, `None` if `name` isn't a playlist
+pub fn rewrite(name: &str, data: &[u8], rewrite: impl FnMut(&str) -> String) -> Option<Vec<u8>> {
+    let format = PlaylistFormat::from_name(name)?;
+    let text = rewrite_paths(&decode(data), format, rewrite);
+
+    // players read a playlist in the encoding it was written in, only `.m3u8` is always UTF-8
+    let is_latin1 =
+        std::str::from_utf8(data).is_err() && !name.to_ascii_lowercase().ends_with(".m3u8");
+    Some(if is_latin1 {
+        encode_latin1(text)
+    } else {
+        text.into_bytes()
+    })
+}
+
+/// Falls back to UTF-8 when an entry now has a character Latin-1 can't hold
+fn encode_latin1(text: String) -> Vec<u8> {
+    text.chars()
+        .map(|c| u8::try_from(c).ok())
+        .collect::<Option<Vec<_>>>()
+        .unwrap_or_else(|| text.into_bytes())
+}
+
+/// Rewrites a playlist file as it will be stored on the server, `None` if `name` isn't a playlist
+pub fn to_library(name: &str, data: &[u8], root: Option<&str>) -> Option<Vec<u8>> {
+    rewrite(name, data, |path| to_library_path(path, root))
+}
+
+/// The library relative paths of every entry
+pub fn entries(text: &str, format: PlaylistFormat) -> Vec<String> {
+    text.lines()
+        .filter_map(|line| entry_path(line, format))
+        .map(|(_, path)| path.to_string())
+        .collect()
+}
+
+fn is_url(path: &str) -> bool {
+    path.contains("://")
+}
+
+fn is_absolute(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    path.starts_with('/')
+        || (bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':')
+}
+
+/// Turns a path written by some device into a library relative one with `/` separators.
+/// Absolute paths outside `root` keep only the file name, which is enough for a flat library.
+pub fn to_library_path(path: &str, root: Option<&str>) -> String {
+    if is_url(path) {
+        return path.to_string();
+    }
+
+    let path = path.replace('\\', "/");
+
+    if !is_absolute(&path) {
+        return path.trim_start_matches("./").to_string();
+    }
+
+    if let Some(root) = root {
+        let root = root.replace('\\', "/");
+        let root = root.trim_end_matches('/');
+
+        let under_root = path
+            .get(..root.len())
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case(root));
+
+        if let Some(relative) = under_root
+            .then(|| path[root.len()..].strip_prefix('/'))
+            .flatten()
+            .filter(|relative| !relative.is_empty())
+        {
+            return relative.to_string();
+        }
+    }
+
+    path.rsplit('/').next().unwrap_or(&path).to_string()
+}
+
+/// The inverse of `to_library_path` for the given layout
+pub fn to_local_path(path: &str, style: &PathStyle) -> String {
+    if is_url(path) {
+        return path.to_string();
+    }
+
+    let relative = path.replace('/', &style.separator.to_string());
+
+    match &style.root {
+        Some(root) => format!(
+            "{}{}{}",
+            root.trim_end_matches(['/', '\\']),
+            style.separator,
+            relative
+        ),
+        None => relative,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            PlaylistFormat::from_name("a.M3U8"),
+            Some(PlaylistFormat::M3u)
+        );
+        assert_eq!(
+            PlaylistFormat::from_name("b.pls"),
+            Some(PlaylistFormat::Pls)
+        );
+        assert_eq!(PlaylistFormat::from_name("song.mp3"), None);
+    }
+
+    #[test]
+    fn test_to_library_path() {
+        let root = Some("/home/me/Music");
+        assert_eq!(to_library_path("/home/me/Music/a.mp3", root), "a.mp3");
+        assert_eq!(to_library_path("C:\\Music\\b.flac", root), "b.flac");
+        assert_eq!(
+            to_library_path("C:\\Music\\b.flac", Some("c:\\music")),
+            "b.flac"
+        );
+        assert_eq!(to_library_path("./c.ogg", root), "c.ogg");
+        assert_eq!(
+            to_library_path("http://radio/stream", root),
+            "http://radio/stream"
+        );
+    }
+
+    #[test]
+    fn test_rewrite_roundtrip() {
+        let local = "#EXTM3U\r\n#EXTINF:100,Song\r\nE:\\Music\\Song.mp3\r\n";
+
+        let library = rewrite_paths(local, PlaylistFormat::M3u, |path| {
+            to_library_path(path, Some("E:\\Music"))
+        });
+        assert_eq!(library, "#EXTM3U\r\n#EXTINF:100,Song\r\nSong.mp3\r\n");
+
+        let style = PathStyle {
+            root: Some("/sdcard/Music".to_string()),
+            separator: '/',
+        };
+        let phone = rewrite_paths(&library, PlaylistFormat::M3u, |path| {
+            to_local_path(path, &style)
+        });
+        assert_eq!(
+            phone,
+            "#EXTM3U\r\n#EXTINF:100,Song\r\n/sdcard/Music/Song.mp3\r\n"
+        );
+    }
+
+    #[test]
+    fn test_rewrite_keeps_latin1() {
+        let latin1 = b"#EXTINF:100,Caf\xe9\n/mnt/music/Caf\xe9.mp3\n";
+
+        let library = to_library("list.m3u", latin1, Some("/mnt/music")).unwrap();
+        assert_eq!(library, b"#EXTINF:100,Caf\xe9\nCaf\xe9.mp3\n");
+
+        // `.m3u8` is UTF-8 by definition, and names Latin-1 can't hold need it as well
+        let library = to_library("list.m3u8", latin1, Some("/mnt/music")).unwrap();
+        assert_eq!(library, "#EXTINF:100,Caf\u{e9}\nCaf\u{e9}.mp3\n".as_bytes());
+        let library = rewrite("list.m3u", latin1, |_| "\u{6771}.mp3".to_string()).unwrap();
+        assert_eq!(library, "#EXTINF:100,Caf\u{e9}\n\u{6771}.mp3\n".as_bytes());
+    }
+
+    #[test]
+    fn test_pls() {
+        let pls = "[playlist]\nFile1=/mnt/music/a.mp3\nTitle1=A\nNumberOfEntries=1\n";
+
+        let library = rewrite_paths(pls, PlaylistFormat::Pls, |path| {
+            to_library_path(path, Some("/mnt/music"))
+        });
+        assert_eq!(
+            library,
+            "[playlist]\nFile1=a.mp3\nTitle1=A\nNumberOfEntries=1\n"
+        );
+        assert_eq!(entries(&library, PlaylistFormat::Pls), vec!["a.mp3"]);
+    }
+}
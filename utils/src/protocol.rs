@@ -0,0 +1,411 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    io::{self, Read, Write},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{cbf, filter::SyncFilter};
+
+/// The protocol this build speaks, sent by the client in the manifest and by the server
+/// in every response
+pub const PROTOCOL_VERSION: u16 = 3;
+/// The oldest protocol this build still understands. Protocol 3 widened the CBF size and
+/// name length prefixes, older clients can't read its entries.
+pub const MIN_PROTOCOL_VERSION: u16 = 3;
+
+/// Content type of the manifest
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+/// Content type of the responses, they are always parsed as an envelope whatever the
+/// headers say, so proxies adding or changing them don't matter
+pub const ENVELOPE_CONTENT_TYPE: &str = "application/vnd.music-sync";
+
+/// Every response starts with this, followed by the protocol version (u16), the length
+/// of the JSON header (u32), the header and a CBF file with the entries.
+/// This framing never changes so that any client can tell it's talking to an
+/// incompatible server.
+const MAGIC: &[u8; 4] = b"MSYN";
+
+/// The magic, the protocol and the length of the JSON header that start every envelope
+pub const ENVELOPE_PREFIX_SIZE: usize = 10;
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// What the client sends to `GET /sync`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The newest protocol the client speaks, manifests without it come from clients
+    /// that predate versioning
+    #[serde(default = "legacy_protocol")]
+    pub protocol: u16,
+    /// Every file the client has that passes its filter
+    pub files: BTreeSet<String>,
+    /// Files the client doesn't have and doesn't want, e.g. because they don't fit in
+    /// `max_size`. The server never sends them.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub skipped: BTreeSet<String>,
+    /// The server only sends files that match it
+    #[serde(default)]
+    pub filter: SyncFilter,
+}
+
+fn legacy_protocol() -> u16 {
+    1
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Self {
+            protocol: PROTOCOL_VERSION,
+            files: BTreeSet::new(),
+            skipped: BTreeSet::new(),
+            filter: SyncFilter::default(),
+        }
+    }
+}
+
+impl Manifest {
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("manifests always serialize")
+    }
+
+    pub fn from_json(data: &[u8]) -> io::Result<Self> {
+        serde_json::from_slice(data).map_err(invalid_data)
+    }
+}
+
+/// The version both sides will use, `None` if the client is too old for this server.
+/// Newer clients are answered with the newest version the server knows, they are
+/// expected to still speak it or to report the mismatch.
+pub fn negotiate(client_protocol: u16) -> Option<u16> {
+    let protocol = client_protocol.min(PROTOCOL_VERSION);
+    (protocol >= MIN_PROTOCOL_VERSION).then_some(protocol)
+}
+
+/// Library names end up in urls and state file names, so they're kept simple
+pub fn is_valid_library_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub version: String,
+    pub min_protocol: u16,
+    pub max_protocol: u16,
+    /// Larger uploads are refused, clients split theirs to stay under it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_upload_bytes: Option<u64>,
+}
+
+impl ServerInfo {
+    pub fn new(version: &str) -> Self {
+        Self {
+            version: version.to_string(),
+            min_protocol: MIN_PROTOCOL_VERSION,
+            max_protocol: PROTOCOL_VERSION,
+            max_upload_bytes: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncStatus {
+    /// Both sides have the same files
+    Synced,
+    /// The server needs the files in `missing` from the client
+    Missing,
+    /// The response holds files for the client, and maybe a `missing` list as well
+    Extra,
+    /// The client speaks a protocol the server doesn't support, see `server`
+    Incompatible,
+}
+
+/// What happened to one file of an upload
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum UploadResult {
+    Written,
+    /// The server already had the exact same file
+    Identical,
+    /// The server refused the file, uploading it again won't help
+    Rejected {
+        reason: String,
+    },
+    /// Writing the file failed, uploading it again later may work
+    Failed {
+        error: String,
+    },
+}
+
+impl fmt::Display for UploadResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Written => write!(f, "written"),
+            Self::Identical => write!(f, "already on the server"),
+            Self::Rejected { reason } => write!(f, "rejected, {}", reason),
+            Self::Failed { error } => write!(f, "failed, {}", error),
+        }
+    }
+}
+
+/// The response envelope of `/sync`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncResponse {
+    pub protocol: u16,
+    pub status: SyncStatus,
+    pub server: ServerInfo,
+    /// Files the server needs from the client
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub missing: BTreeSet<String>,
+    /// The result for every file of an upload, only set by `POST /sync`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub uploads: BTreeMap<String, UploadResult>,
+    /// Files for the client, they are stored after the JSON header as a CBF file
+    #[serde(skip)]
+    pub entries: cbf::FileEntries,
+}
+
+impl SyncResponse {
+    pub fn new(protocol: u16, status: SyncStatus, server: ServerInfo) -> Self {
+        Self {
+            protocol,
+            status,
+            server,
+            missing: BTreeSet::new(),
+            uploads: BTreeMap::new(),
+            entries: cbf::FileEntries::new(),
+        }
+    }
+
+    /// Writes the envelope with `entries` instead of `self.entries`, so the server can send
+    /// files without copying them
+    pub fn write_with<W, V, S>(&self, writer: &mut W, entries: &HashMap<S, V>) -> io::Result<()>
+    where
+        W: Write,
+        V: AsRef<Vec<u8>>,
+        S: AsRef<str>,
+    {
+        let header = serde_json::to_vec(self).map_err(invalid_data)?;
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&self.protocol.to_le_bytes())?;
+        writer.write_all(&(header.len() as u32).to_le_bytes())?;
+        writer.write_all(&header)?;
+        cbf::write(writer, entries, None)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        self.write_with(&mut buffer, &self.entries)
+            .expect("writing to a Vec can't fail");
+        buffer
+    }
+
+    /// Checks the start of an envelope and returns the length of the JSON header after it.
+    /// Fails with a readable error when the data isn't an envelope or uses a protocol
+    /// this build doesn't understand.
+    pub fn header_length(prefix: &[u8; ENVELOPE_PREFIX_SIZE]) -> io::Result<usize> {
+        let (magic, rest) = prefix.split_at(MAGIC.len());
+        if magic != MAGIC {
+            return Err(invalid_data("Not a sync response"));
+        }
+
+        let protocol = u16::from_le_bytes([rest[0], rest[1]]);
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol) {
+            return Err(invalid_data(format!(
+                "The server speaks protocol {}, this client supports {} to {}",
+                protocol, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            )));
+        }
+
+        Ok(u32::from_le_bytes(rest[2..6].try_into().unwrap()) as usize)
+    }
+
+    /// The response described by a JSON header, without its entries
+    pub fn from_header(header: &[u8]) -> io::Result<Self> {
+        serde_json::from_slice(header).map_err(invalid_data)
+    }
+
+    /// Reads a whole envelope, see `header_length` for the errors
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut prefix = [0; ENVELOPE_PREFIX_SIZE];
+        reader.read_exact(&mut prefix)?;
+        let mut header = vec![0; Self::header_length(&prefix)?];
+        reader.read_exact(&mut header)?;
+
+        let mut response = Self::from_header(&header)?;
+        let (_, entries) = cbf::read(reader)?;
+        response.entries = entries;
+
+        Ok(response)
+    }
+}
+
+/// Why a file was moved to the server's trash
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrashReason {
+    /// An upload overwrote it
+    Replaced,
+    Deleted,
+}
+
+impl fmt::Display for TrashReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Replaced => write!(f, "replaced"),
+            Self::Deleted => write!(f, "deleted"),
+        }
+    }
+}
+
+/// An earlier version of a file, kept in the server's trash, as listed by `GET /trash`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileVersion {
+    pub name: String,
+    /// When the file left the library, in milliseconds since the Unix epoch. It's also
+    /// what restoring the version asks for.
+    pub version: u64,
+    pub reason: TrashReason,
+    pub size: u64,
+}
+
+/// Hex SHA-256 of a file, what snapshots refer to its content by
+pub fn content_hash(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// A file of a snapshot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotFile {
+    /// See `content_hash`
+    pub hash: String,
+    pub size: u64,
+}
+
+/// Every file of a library at one point in time, as returned by `GET /snapshots/{id}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// When it was taken, in milliseconds since the Unix epoch
+    pub id: u64,
+    pub files: BTreeMap<String, SnapshotFile>,
+}
+
+/// A snapshot as listed by `GET /snapshots`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotInfo {
+    pub id: u64,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+impl From<&Snapshot> for SnapshotInfo {
+    fn from(snapshot: &Snapshot) -> Self {
+        Self {
+            id: snapshot.id,
+            files: snapshot.files.len(),
+            bytes: snapshot.files.values().map(|file| file.size).sum(),
+        }
+    }
+}
+
+/// The JSON body of every error response of the server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    /// A stable identifier such as `invalid_upload`, meant for programs
+    pub error: String,
+    /// Meant for humans
+    pub message: String,
+}
+
+impl ErrorResponse {
+    /// Turns an error body into a message for the user, falling back to the raw text
+    /// for responses that don't come from the server (e.g. a proxy)
+    pub fn describe(body: &[u8]) -> String {
+        match serde_json::from_slice::<Self>(body) {
+            Ok(response) => response.message,
+            Err(_) => String::from_utf8_lossy(body).into_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_hash() {
+        assert_eq!(
+            content_hash(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_manifest_roundtrip() {
+        let mut manifest = Manifest::default();
+        manifest.files.insert("a|b.mp3".to_string());
+        manifest.files.insert("synced".to_string());
+        manifest.skipped.insert("big.flac".to_string());
+        manifest.filter.add("exclude", "*demo*");
+
+        let parsed = Manifest::from_json(&manifest.to_json()).unwrap();
+        assert_eq!(parsed, manifest);
+
+        // manifests from before versioning
+        let parsed = Manifest::from_json(br#"{"files":["x.mp3"]}"#).unwrap();
+        assert_eq!(parsed.protocol, 1);
+        assert!(parsed.skipped.is_empty() && parsed.filter.is_empty());
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(1), None);
+        assert_eq!(negotiate(PROTOCOL_VERSION), Some(PROTOCOL_VERSION));
+        assert_eq!(negotiate(PROTOCOL_VERSION + 1), Some(PROTOCOL_VERSION));
+    }
+
+    #[test]
+    fn test_response_roundtrip() {
+        let mut response = SyncResponse::new(
+            PROTOCOL_VERSION,
+            SyncStatus::Extra,
+            ServerInfo::new("1.0.0"),
+        );
+        response.missing.insert("synced".to_string());
+        response.uploads.insert(
+            "bad.mp3".to_string(),
+            UploadResult::Rejected {
+                reason: "invalid name".to_string(),
+            },
+        );
+        response
+            .entries
+            .insert("song.mp3".to_string(), vec![1, 2, 3]);
+
+        let bytes = response.to_bytes();
+        let parsed = SyncResponse::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!(parsed, response);
+    }
+
+    #[test]
+    fn test_response_errors() {
+        assert!(SyncResponse::read(&mut b"synced".as_slice()).is_err());
+
+        let response = SyncResponse::new(
+            PROTOCOL_VERSION + 1,
+            SyncStatus::Synced,
+            ServerInfo::new("9.0.0"),
+        );
+        let err = SyncResponse::read(&mut response.to_bytes().as_slice()).unwrap_err();
+        assert!(err.to_string().contains("protocol"));
+    }
+}
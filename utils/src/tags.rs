@@ -0,0 +1,800 @@
+use serde::{Deserialize, Serialize};
+
+/// Everything the server knows about a track apart from its file name.
+/// Fields are `None` when the file doesn't carry them or the format isn't recognized.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrackInfo {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<u32>,
+    pub track_number: Option<u32>,
+    pub duration_ms: Option<u64>,
+    /// In kbit/s
+    pub bitrate: Option<u32>,
+}
+
+impl TrackInfo {
+    /// Looks up a field by the same names used in the `/library` query filters
+    pub fn field(&self, name: &str) -> Option<String> {
+        match name {
+            "title" => self.title.clone(),
+            "artist" => self.artist.clone(),
+            "album" => self.album.clone(),
+            "genre" => self.genre.clone(),
+            "year" => self.year.map(|year| year.to_string()),
+            "track" | "track_number" => self.track_number.map(|track| track.to_string()),
+            _ => None,
+        }
+    }
+
+    fn set_text(&mut self, key: &str, value: String) {
+        let value = value.trim_end_matches('\0').trim().to_string();
+        if value.is_empty() {
+            return;
+        }
+
+        match key {
+            "TITLE" => self.title = Some(value),
+            "ARTIST" => self.artist = Some(value),
+            "ALBUM" => self.album = Some(value),
+            "GENRE" => self.genre = Some(value),
+            "DATE" | "YEAR" => self.year = leading_number(&value),
+            "TRACKNUMBER" => self.track_number = leading_number(&value),
+            _ => {}
+        }
+    }
+
+    fn set_bitrate_from_size(&mut self, size: usize) {
+        if self.bitrate.is_none() {
+            if let Some(duration_ms) = self.duration_ms.filter(|duration| *duration > 0) {
+                self.bitrate = Some((size as u64 * 8 / duration_ms) as u32);
+            }
+        }
+    }
+}
+
+/// Detects the container from its magic bytes, the extension is never trusted
+pub fn parse(data: &[u8]) -> TrackInfo {
+    let mut info = TrackInfo::default();
+
+    if data.starts_with(b"fLaC") {
+        parse_flac(data, &mut info);
+    } else if data.starts_with(b"OggS") {
+        parse_ogg(data, &mut info);
+    } else if data.len() >= 8 && &data[4..8] == b"ftyp" {
+        parse_mp4(data, &mut info);
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WAVE" {
+        parse_wav(data, &mut info);
+    } else {
+        let audio_start = parse_id3v2(data, &mut info);
+        parse_mpeg_audio(&data[audio_start.min(data.len())..], &mut info);
+    }
+
+    info.set_bitrate_from_size(data.len());
+    info
+}
+
+fn leading_number(value: &str) -> Option<u32> {
+    let digits: String = value
+        .trim()
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    digits.parse().ok()
+}
+
+fn u16_be(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_be(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn u64_be(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(
+        data.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+fn u32_le(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn u64_le(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        data.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+fn syncsafe(data: &[u8], offset: usize) -> Option<usize> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(
+        bytes
+            .iter()
+            .fold(0, |acc, byte| (acc << 7) | (*byte & 0x7f) as usize),
+    )
+}
+
+/// Returns the offset where the audio frames start, 0 if there is no tag
+fn parse_id3v2(data: &[u8], info: &mut TrackInfo) -> usize {
+    if data.len() < 10 || !data.starts_with(b"ID3") {
+        return 0;
+    }
+
+    let version = data[3];
+    let flags = data[5];
+    let Some(tag_size) = syncsafe(data, 6) else {
+        return 0;
+    };
+    let end = (10 + tag_size).min(data.len());
+
+    let mut offset = 10;
+    if flags & 0x40 != 0 && version >= 3 {
+        let extended_size = if version == 4 {
+            syncsafe(data, offset)
+        } else {
+            u32_be(data, offset).map(|size| size as usize + 4)
+        };
+        offset += extended_size.unwrap_or(0);
+    }
+
+    // v2.2 uses 3 byte ids and sizes, v2.3 and v2.4 use 4 bytes
+    let (id_len, header_len) = if version == 2 { (3, 6) } else { (4, 10) };
+
+    while offset + header_len <= end {
+        let id = &data[offset..offset + id_len];
+        if id[0] == 0 {
+            break; // padding
+        }
+
+        let size = match version {
+            2 => data[offset + 3..offset + 6]
+                .iter()
+                .fold(0, |acc, byte| (acc << 8) | *byte as usize),
+            4 => syncsafe(data, offset + 4).unwrap_or(0),
+            _ => u32_be(data, offset + 4).unwrap_or(0) as usize,
+        };
+
+        let body_start = offset + header_len;
+        let body_end = body_start + size;
+        if body_end > end {
+            break;
+        }
+        let body = &data[body_start..body_end];
+
+        let key = match id {
+            b"TIT2" | b"TT2" => Some("TITLE"),
+            b"TPE1" | b"TP1" => Some("ARTIST"),
+            b"TALB" | b"TAL" => Some("ALBUM"),
+            b"TCON" | b"TCO" => Some("GENRE"),
+            b"TYER" | b"TDRC" | b"TYE" => Some("YEAR"),
+            b"TRCK" | b"TRK" => Some("TRACKNUMBER"),
+            b"TLEN" | b"TLE" => Some("TLEN"),
+            _ => None,
+        };
+
+        if let Some(key) = key {
+            let text = id3_text(body);
+            if key == "TLEN" {
+                info.duration_ms = text.trim_end_matches('\0').trim().parse().ok();
+            } else if key == "GENRE" {
+                info.set_text(key, id3_genre(&text));
+            } else {
+                info.set_text(key, text);
+            }
+        }
+
+        offset = body_end;
+    }
+
+    // footer is only present in v2.4
+    if version == 4 && flags & 0x10 != 0 {
+        end + 10
+    } else {
+        end
+    }
+}
+
+fn id3_text(body: &[u8]) -> String {
+    let Some((&encoding, text)) = body.split_first() else {
+        return String::new();
+    };
+
+    match encoding {
+        0 => text.iter().map(|byte| *byte as char).collect(),
+        1 | 2 => {
+            let (big_endian, text) = match text {
+                [0xfe, 0xff, rest @ ..] => (true, rest),
+                [0xff, 0xfe, rest @ ..] => (false, rest),
+                _ => (encoding == 2, text),
+            };
+            let units: Vec<u16> = text
+                .chunks_exact(2)
+                .map(|pair| {
+                    if big_endian {
+                        u16::from_be_bytes([pair[0], pair[1]])
+                    } else {
+                        u16::from_le_bytes([pair[0], pair[1]])
+                    }
+                })
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(text).into_owned(),
+    }
+}
+
+/// ID3v1 style genres are stored as "(17)" or a bare number
+fn id3_genre(text: &str) -> String {
+    const GENRES: [&str; 26] = [
+        "Blues",
+        "Classic Rock",
+        "Country",
+        "Dance",
+        "Disco",
+        "Funk",
+        "Grunge",
+        "Hip-Hop",
+        "Jazz",
+        "Metal",
+        "New Age",
+        "Oldies",
+        "Other",
+        "Pop",
+        "R&B",
+        "Rap",
+        "Reggae",
+        "Rock",
+        "Techno",
+        "Industrial",
+        "Alternative",
+        "Ska",
+        "Death Metal",
+        "Pranks",
+        "Soundtrack",
+        "Euro-Techno",
+    ];
+
+    let text = text.trim_end_matches('\0').trim();
+    let number = text.trim_start_matches('(').trim_end_matches(')');
+
+    match number.parse::<usize>() {
+        Ok(index) if index < GENRES.len() => GENRES[index].to_string(),
+        _ => text.to_string(),
+    }
+}
+
+fn parse_mpeg_audio(data: &[u8], info: &mut TrackInfo) {
+    const BITRATES_V1_L3: [u32; 16] = [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 0,
+    ];
+    const BITRATES_V2_L3: [u32; 16] = [
+        0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160, 0,
+    ];
+    const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+    // only look at the start of the file, junk before the first frame is rare
+    let search_end = data.len().min(64 * 1024);
+    let Some(start) = (0..search_end.saturating_sub(4))
+        .find(|&i| data[i] == 0xff && data[i + 1] & 0xe0 == 0xe0 && data[i + 1] & 0x06 == 0x02)
+    else {
+        return;
+    };
+
+    let header = &data[start..];
+    let version_bits = (header[1] >> 3) & 0x03; // 3 = MPEG1, 2 = MPEG2, 0 = MPEG2.5
+    let bitrate_index = (header[2] >> 4) as usize;
+    let sample_rate_index = ((header[2] >> 2) & 0x03) as usize;
+    let channel_mode = header[3] >> 6;
+
+    if version_bits == 1 || sample_rate_index == 3 {
+        return;
+    }
+
+    let mpeg1 = version_bits == 3;
+    let bitrate = if mpeg1 {
+        BITRATES_V1_L3[bitrate_index]
+    } else {
+        BITRATES_V2_L3[bitrate_index]
+    };
+    let sample_rate = SAMPLE_RATES[sample_rate_index]
+        >> match version_bits {
+            3 => 0,
+            2 => 1,
+            _ => 2,
+        };
+    let samples_per_frame: u64 = if mpeg1 { 1152 } else { 576 };
+
+    // a Xing/Info header in the first frame means VBR and holds the frame count
+    let side_info = match (mpeg1, channel_mode == 3) {
+        (true, false) => 32,
+        (true, true) => 17,
+        (false, false) => 17,
+        (false, true) => 9,
+    };
+    let xing = 4 + side_info;
+    if let Some(tag) = header.get(xing..xing + 4) {
+        if tag == b"Xing" || tag == b"Info" {
+            let flags = u32_be(header, xing + 4).unwrap_or(0);
+            if flags & 0x01 != 0 {
+                if let Some(frames) = u32_be(header, xing + 8) {
+                    let duration_ms = frames as u64 * samples_per_frame * 1000 / sample_rate as u64;
+                    info.duration_ms.get_or_insert(duration_ms);
+                    return;
+                }
+            }
+        }
+    }
+
+    if bitrate > 0 {
+        info.bitrate = Some(bitrate);
+        let audio_bytes = (data.len() - start) as u64;
+        info.duration_ms
+            .get_or_insert(audio_bytes * 8 / bitrate as u64);
+    }
+}
+
+fn parse_vorbis_comments(data: &[u8], info: &mut TrackInfo) {
+    let Some(vendor_length) = u32_le(data, 0) else {
+        return;
+    };
+    let mut offset = 4 + vendor_length as usize;
+    let Some(count) = u32_le(data, offset) else {
+        return;
+    };
+    offset += 4;
+
+    for _ in 0..count {
+        let Some(length) = u32_le(data, offset) else {
+            return;
+        };
+        offset += 4;
+        let Some(comment) = data.get(offset..offset + length as usize) else {
+            return;
+        };
+        offset += length as usize;
+
+        let comment = String::from_utf8_lossy(comment);
+        if let Some((key, value)) = comment.split_once('=') {
+            info.set_text(&key.to_ascii_uppercase(), value.to_string());
+        }
+    }
+}
+
+fn parse_flac(data: &[u8], info: &mut TrackInfo) {
+    let mut offset = 4;
+
+    while let Some(header) = data.get(offset..offset + 4) {
+        let last = header[0] & 0x80 != 0;
+        let block_type = header[0] & 0x7f;
+        let length = (header[1] as usize) << 16 | (header[2] as usize) << 8 | header[3] as usize;
+        let Some(block) = data.get(offset + 4..offset + 4 + length) else {
+            return;
+        };
+
+        match block_type {
+            0 if block.len() >= 18 => {
+                // STREAMINFO: 20 bits of sample rate then 36 bits of total samples
+                let sample_rate =
+                    (block[10] as u64) << 12 | (block[11] as u64) << 4 | (block[12] as u64) >> 4;
+                let total_samples =
+                    ((block[13] & 0x0f) as u64) << 32 | u32_be(block, 14).unwrap_or(0) as u64;
+                if sample_rate > 0 && total_samples > 0 {
+                    info.duration_ms = Some(total_samples * 1000 / sample_rate);
+                }
+            }
+            4 => parse_vorbis_comments(block, info),
+            _ => {}
+        }
+
+        if last {
+            return;
+        }
+        offset += 4 + length;
+    }
+}
+
+/// Reassembles the first `count` packets of the first logical stream
+fn ogg_packets(data: &[u8], count: usize) -> Vec<Vec<u8>> {
+    let mut packets = Vec::new();
+    let mut current = Vec::new();
+    let mut offset = 0;
+
+    while packets.len() < count {
+        let Some(header) = data.get(offset..offset + 27) else {
+            break;
+        };
+        if &header[0..4] != b"OggS" {
+            break;
+        }
+
+        let segments = header[26] as usize;
+        let Some(lacing) = data.get(offset + 27..offset + 27 + segments) else {
+            break;
+        };
+        let mut body = offset + 27 + segments;
+
+        for &length in lacing {
+            let Some(segment) = data.get(body..body + length as usize) else {
+                return packets;
+            };
+            current.extend_from_slice(segment);
+            body += length as usize;
+
+            if length < 255 {
+                packets.push(std::mem::take(&mut current));
+                if packets.len() == count {
+                    break;
+                }
+            }
+        }
+
+        offset = body;
+    }
+
+    packets
+}
+
+/// The granule position of the last page is the total number of samples
+fn ogg_last_granule(data: &[u8]) -> Option<u64> {
+    let search_start = data.len().saturating_sub(64 * 1024);
+    let position = data[search_start..]
+        .windows(4)
+        .rposition(|window| window == b"OggS")?;
+    u64_le(data, search_start + position + 6)
+}
+
+fn parse_ogg(data: &[u8], info: &mut TrackInfo) {
+    let packets = ogg_packets(data, 2);
+    let [identification, comments] = packets.as_slice() else {
+        return;
+    };
+
+    let (sample_rate, pre_skip) = if identification.starts_with(b"\x01vorbis") {
+        if let Some(nominal) = u32_le(identification, 20).filter(|bitrate| *bitrate > 0) {
+            info.bitrate = Some(nominal / 1000);
+        }
+        if let Some(comments) = comments.strip_prefix(b"\x03vorbis") {
+            parse_vorbis_comments(comments, info);
+        }
+        (u32_le(identification, 12).unwrap_or(0) as u64, 0)
+    } else if identification.starts_with(b"OpusHead") {
+        if let Some(comments) = comments.strip_prefix(b"OpusTags") {
+            parse_vorbis_comments(comments, info);
+        }
+        // opus granule positions are always at 48 kHz
+        let pre_skip = identification
+            .get(10..12)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as u64)
+            .unwrap_or(0);
+        (48000, pre_skip)
+    } else {
+        return;
+    };
+
+    if let Some(granule) = ogg_last_granule(data) {
+        if sample_rate > 0 && granule != u64::MAX {
+            info.duration_ms = duration_ms(granule.saturating_sub(pre_skip), sample_rate);
+        }
+    }
+}
+
+/// `ticks` at `rate` per second in milliseconds, the 64 bit counts of ogg and mp4 can be
+/// too large to multiply in place
+fn duration_ms(ticks: u64, rate: u64) -> Option<u64> {
+    u64::try_from(ticks as u128 * 1000 / rate as u128).ok()
+}
+
+/// Calls `visit` with the type and body of every atom in `data`
+fn mp4_atoms<'a>(data: &'a [u8], mut visit: impl FnMut(&'a [u8], &'a [u8])) {
+    let mut offset = 0;
+
+    while let Some(size) = u32_be(data, offset) {
+        let Some(kind) = data.get(offset + 4..offset + 8) else {
+            return;
+        };
+
+        let (header_len, size) = match size {
+            0 => (8, data.len() - offset),
+            1 => match u64_be(data, offset + 8).and_then(|size| usize::try_from(size).ok()) {
+                Some(size) => (16, size),
+                None => return,
+            },
+            size => (8, size as usize),
+        };
+
+        // a size past the end of the file, however large, ends the parsing
+        let Some(end) = offset.checked_add(size) else {
+            return;
+        };
+        if size < header_len {
+            return;
+        }
+        let Some(body) = data.get(offset + header_len..end) else {
+            return;
+        };
+
+        visit(kind, body);
+        offset = end;
+    }
+}
+
+fn mp4_child<'a>(data: &'a [u8], wanted: &[u8]) -> Option<&'a [u8]> {
+    let mut found = None;
+    mp4_atoms(data, |kind, body| {
+        if found.is_none() && kind == wanted {
+            found = Some(body);
+        }
+    });
+    found
+}
+
+fn parse_mp4(data: &[u8], info: &mut TrackInfo) {
+    let Some(moov) = mp4_child(data, b"moov") else {
+        return;
+    };
+
+    if let Some(mvhd) = mp4_child(moov, b"mvhd") {
+        let (timescale, duration) = if mvhd.first() == Some(&1) {
+            (u32_be(mvhd, 20), u64_be(mvhd, 24))
+        } else {
+            (u32_be(mvhd, 12), u32_be(mvhd, 16).map(u64::from))
+        };
+
+        if let (Some(timescale), Some(duration)) = (timescale, duration) {
+            if timescale > 0 {
+                info.duration_ms = duration_ms(duration, timescale as u64);
+            }
+        }
+    }
+
+    // meta is a full box, so the children start after 4 bytes of version and flags
+    let Some(ilst) = mp4_child(moov, b"udta")
+        .and_then(|udta| mp4_child(udta, b"meta"))
+        .and_then(|meta| meta.get(4..))
+        .and_then(|meta| mp4_child(meta, b"ilst"))
+    else {
+        return;
+    };
+
+    mp4_atoms(ilst, |kind, body| {
+        // data atom body: 4 bytes of type, 4 bytes of locale then the value
+        let Some(value) = mp4_child(body, b"data").and_then(|data| data.get(8..)) else {
+            return;
+        };
+
+        match kind {
+            b"\xa9nam" => info.set_text("TITLE", String::from_utf8_lossy(value).into_owned()),
+            b"\xa9ART" => info.set_text("ARTIST", String::from_utf8_lossy(value).into_owned()),
+            b"\xa9alb" => info.set_text("ALBUM", String::from_utf8_lossy(value).into_owned()),
+            b"\xa9gen" => info.set_text("GENRE", String::from_utf8_lossy(value).into_owned()),
+            b"\xa9day" => info.set_text("DATE", String::from_utf8_lossy(value).into_owned()),
+            b"trkn" => {
+                info.track_number = u16_be(value, 2).map(u32::from).filter(|track| *track > 0)
+            }
+            _ => {}
+        }
+    });
+}
+
+fn parse_wav(data: &[u8], info: &mut TrackInfo) {
+    let mut offset = 12;
+    let mut byte_rate = None;
+
+    while let (Some(kind), Some(size)) = (data.get(offset..offset + 4), u32_le(data, offset + 4)) {
+        let body = offset + 8;
+
+        match kind {
+            b"fmt " => byte_rate = u32_le(data, body + 8).filter(|rate| *rate > 0),
+            b"data" => {
+                if let Some(byte_rate) = byte_rate {
+                    info.duration_ms = Some(size as u64 * 1000 / byte_rate as u64);
+                    info.bitrate = Some(byte_rate * 8 / 1000);
+                }
+                return;
+            }
+            _ => {}
+        }
+
+        // chunks are padded to an even size
+        offset = body + size as usize + (size as usize & 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vorbis_comments(comments: &[&str]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&4u32.to_le_bytes());
+        data.extend_from_slice(b"test");
+        data.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            data.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            data.extend_from_slice(comment.as_bytes());
+        }
+        data
+    }
+
+    fn id3_frame(id: &[u8], text: &str) -> Vec<u8> {
+        let mut frame = id.to_vec();
+        frame.extend_from_slice(&(text.len() as u32 + 1).to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 3]);
+        frame.extend_from_slice(text.as_bytes());
+        frame
+    }
+
+    #[test]
+    fn test_id3v2_and_mpeg_frames() {
+        let mut frames = Vec::new();
+        frames.extend(id3_frame(b"TIT2", "Song"));
+        frames.extend(id3_frame(b"TPE1", "Artist"));
+        frames.extend(id3_frame(b"TALB", "Album"));
+        frames.extend(id3_frame(b"TRCK", "3/12"));
+        frames.extend(id3_frame(b"TYER", "2004"));
+        frames.extend(id3_frame(b"TCON", "(17)"));
+
+        let mut data = b"ID3\x03\x00\x00".to_vec();
+        let size = frames.len();
+        data.extend_from_slice(&[
+            (size >> 21) as u8 & 0x7f,
+            (size >> 14) as u8 & 0x7f,
+            (size >> 7) as u8 & 0x7f,
+            size as u8 & 0x7f,
+        ]);
+        data.extend(frames);
+
+        // one second of 128 kbit/s MPEG1 layer 3 at 44.1 kHz
+        let mut audio = vec![0u8; 16000];
+        audio[0..4].copy_from_slice(&[0xff, 0xfb, 0x90, 0x00]);
+        data.extend(audio);
+
+        let info = parse(&data);
+        assert_eq!(info.title.as_deref(), Some("Song"));
+        assert_eq!(info.artist.as_deref(), Some("Artist"));
+        assert_eq!(info.album.as_deref(), Some("Album"));
+        assert_eq!(info.genre.as_deref(), Some("Rock"));
+        assert_eq!(info.year, Some(2004));
+        assert_eq!(info.track_number, Some(3));
+        assert_eq!(info.bitrate, Some(128));
+        assert_eq!(info.duration_ms, Some(1000));
+    }
+
+    #[test]
+    fn test_flac() {
+        let mut stream_info = vec![0u8; 34];
+        // 44100 Hz and 441000 samples
+        stream_info[10] = (44100 >> 12) as u8;
+        stream_info[11] = (44100 >> 4) as u8;
+        stream_info[12] = ((44100 & 0x0f) << 4) as u8;
+        stream_info[14..18].copy_from_slice(&441000u32.to_be_bytes());
+
+        let comments = vorbis_comments(&["ARTIST=Someone", "title=Track", "TRACKNUMBER=7"]);
+
+        let mut data = b"fLaC".to_vec();
+        data.extend_from_slice(&[0, 0, 0, 34]);
+        data.extend(stream_info);
+        data.push(0x84);
+        data.extend_from_slice(&(comments.len() as u32).to_be_bytes()[1..]);
+        data.extend(comments);
+
+        let info = parse(&data);
+        assert_eq!(info.artist.as_deref(), Some("Someone"));
+        assert_eq!(info.title.as_deref(), Some("Track"));
+        assert_eq!(info.track_number, Some(7));
+        assert_eq!(info.duration_ms, Some(10000));
+    }
+
+    #[test]
+    fn test_ogg_opus() {
+        fn page(granule: u64, packets: &[&[u8]]) -> Vec<u8> {
+            let mut page = b"OggS\x00\x00".to_vec();
+            page.extend_from_slice(&granule.to_le_bytes());
+            page.extend_from_slice(&[0; 12]);
+            let mut lacing = Vec::new();
+            for packet in packets {
+                lacing.extend(std::iter::repeat_n(255, packet.len() / 255));
+                lacing.push((packet.len() % 255) as u8);
+            }
+            page.push(lacing.len() as u8);
+            page.extend(lacing);
+            for packet in packets {
+                page.extend_from_slice(packet);
+            }
+            page
+        }
+
+        let mut head = b"OpusHead\x01\x02".to_vec();
+        head.extend_from_slice(&0u16.to_le_bytes());
+        head.extend_from_slice(&48000u32.to_le_bytes());
+
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend(vorbis_comments(&["ALBUM=Live", "DATE=1999-01-01"]));
+
+        let mut data = page(0, &[&head]);
+        data.extend(page(0, &[&tags]));
+        data.extend(page(48000 * 3, &[&[0u8; 10]]));
+
+        let info = parse(&data);
+        assert_eq!(info.album.as_deref(), Some("Live"));
+        assert_eq!(info.year, Some(1999));
+        assert_eq!(info.duration_ms, Some(3000));
+
+        // a granule position near the end of the range doesn't overflow
+        data.extend(page(u64::MAX - 1, &[&[0u8; 10]]));
+        assert_eq!(parse(&data).duration_ms, Some((u64::MAX - 1) / 48));
+    }
+
+    #[test]
+    fn test_mp4() {
+        fn atom(kind: &[u8], body: &[u8]) -> Vec<u8> {
+            let mut atom = (body.len() as u32 + 8).to_be_bytes().to_vec();
+            atom.extend_from_slice(kind);
+            atom.extend_from_slice(body);
+            atom
+        }
+        fn item(kind: &[u8], value: &[u8]) -> Vec<u8> {
+            let mut data = vec![0, 0, 0, 1, 0, 0, 0, 0];
+            data.extend_from_slice(value);
+            atom(kind, &atom(b"data", &data))
+        }
+
+        let mut mvhd = vec![0u8; 20];
+        mvhd[12..16].copy_from_slice(&1000u32.to_be_bytes());
+        mvhd[16..20].copy_from_slice(&5000u32.to_be_bytes());
+
+        let mut ilst = item(b"\xa9nam", b"Name");
+        ilst.extend(item(b"\xa9ART", b"Band"));
+        ilst.extend(item(b"trkn", &[0, 0, 0, 2, 0, 10, 0, 0]));
+
+        let mut meta = vec![0u8; 4];
+        meta.extend(atom(b"ilst", &ilst));
+
+        let mut moov = atom(b"mvhd", &mvhd);
+        moov.extend(atom(b"udta", &atom(b"meta", &meta)));
+
+        let mut data = atom(b"ftyp", b"M4A \x00\x00\x00\x00");
+        data.extend(atom(b"moov", &moov));
+
+        let info = parse(&data);
+        assert_eq!(info.title.as_deref(), Some("Name"));
+        assert_eq!(info.artist.as_deref(), Some("Band"));
+        assert_eq!(info.track_number, Some(2));
+        assert_eq!(info.duration_ms, Some(5000));
+
+        // a 64 bit duration is too large to multiply in place
+        let mut mvhd = vec![1u8; 32];
+        mvhd[20..24].copy_from_slice(&44100u32.to_be_bytes());
+        mvhd[24..32].copy_from_slice(&u64::MAX.to_be_bytes());
+        let mut data = atom(b"ftyp", b"M4A \x00\x00\x00\x00");
+        data.extend(atom(b"moov", &atom(b"mvhd", &mvhd)));
+        assert_eq!(parse(&data).duration_ms, Some(418_293_516_410_647_428));
+        mvhd[20..24].copy_from_slice(&1u32.to_be_bytes());
+        let mut data = atom(b"ftyp", b"M4A \x00\x00\x00\x00");
+        data.extend(atom(b"moov", &atom(b"mvhd", &mvhd)));
+        assert_eq!(parse(&data).duration_ms, None);
+
+        // a 64 bit size that overflows the offset ends the parsing instead of panicking
+        let mut data = atom(b"ftyp", b"M4A \x00\x00\x00\x00");
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(b"moov");
+        data.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert_eq!(parse(&data).title, None);
+    }
+
+    #[test]
+    fn test_unknown_format() {
+        assert_eq!(parse(b"just some text"), TrackInfo::default());
+        assert_eq!(parse(&[]), TrackInfo::default());
+    }
+}